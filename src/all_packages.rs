@@ -1,68 +1,306 @@
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
 use crate::index::{resolve_reference, GlobalIndex};
 use crate::line_index::LineIndex;
+use anyhow::{anyhow, bail, Context, Result};
 use rnix::ast::AstToken;
-use rnix::ast::{Attr, AttrSet, Expr, HasEntry, InterpolPart};
+use rnix::ast::{Attr, AttrSet, BinOpKind, Expr, HasEntry, InterpolPart};
 use rnix::NixLanguage;
-use rnix::NodeOrToken::Token;
+use rnix::NodeOrToken::{self, Token};
 use rnix::Root;
+use rnix::SyntaxElement;
 use rnix::SyntaxKind;
 use rnix::SyntaxNode;
 use rowan::api::Language;
 use rowan::ast::AstNode;
 use rowan::GreenToken;
+use serde::Serialize;
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::read_to_string;
 use std::fs::File;
 use std::io::Write;
+use std::ops::{Range, RangeInclusive};
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub struct Entry {
     pub index: usize,
     pub line: usize,
+    pub column: usize,
+    // The line the definition ends on
+    pub end_line: usize,
+    // The bytes the definition spans in the file
+    pub range: Range<usize>,
     pub path: PathBuf,
+    // Comments on the lines directly above the definition and after it on the same line
+    pub comments: Vec<String>,
+    // The children of the attribute set making up the definition, including its comments
+    pub indices: RangeInclusive<usize>,
 }
 
 #[derive(Debug)]
 pub struct AllPackages {
-    path: PathBuf,
+    root: PathBuf,
+    // Relative to the root
+    pub path: PathBuf,
     syntax_node: SyntaxNode,
     attributes_to_remove: Vec<String>,
+    // Attributes whose package path is replaced, with the new path reference
+    paths_to_rewrite: Vec<(String, String)>,
     pub entries: HashMap<String, Entry>,
+    // The attribute of each entry by the byte offset it starts at
+    entry_offsets: BTreeMap<usize, String>,
+    // The attributes of each package file, in definition order
+    by_path: HashMap<PathBuf, Vec<String>>,
+    // The attributes by their lowercased name, in definition order
+    by_folded_name: HashMap<String, Vec<String>>,
+    // Definitions that were skipped while parsing
+    pub diagnostics: Vec<Diagnostic>,
+    // Definitions that aren't entries, in the order they're defined in
+    pub blocked: Vec<Blocked>,
+    // Attributes that are defined more than once, which Nix rejects
+    pub duplicates: Vec<Duplicate>,
+}
+
+// Why an attribute can't be migrated
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockReason {
+    // The value isn't of the form `callPackage <path> <arguments>`
+    NotCallPackage,
+    // The path is interpolated or otherwise not a single literal
+    NonLiteralPath,
+    // The path doesn't resolve to a file
+    InvalidPath,
+    NonEmptyArguments,
+    AttributePath,
+    NonIdentifier,
+    // One of the package's files references a path outside its directory
+    EscapingReference,
+    // One of the package's files is referenced from outside
+    ExternalReferencer,
+    // The name isn't supported for unit directories
+    UnsupportedName,
+    // The unit directory already exists
+    ExistingUnitDir,
+    // Another attribute with the same package file is migrated instead
+    SharedPackageFile,
+    // The attribute is defined more than once
+    DuplicateDefinition,
+    // The unit directory would only differ by case from another one
+    CaseCollision,
+    // The attribute is used by name within the `rec` attribute set
+    RecursiveReference,
+    // The migration was refused for a reason without its own category
+    Other,
+}
+
+impl BlockReason {
+    pub const ALL: [BlockReason; 15] = [
+        BlockReason::NotCallPackage,
+        BlockReason::NonLiteralPath,
+        BlockReason::InvalidPath,
+        BlockReason::NonEmptyArguments,
+        BlockReason::AttributePath,
+        BlockReason::NonIdentifier,
+        BlockReason::EscapingReference,
+        BlockReason::ExternalReferencer,
+        BlockReason::UnsupportedName,
+        BlockReason::ExistingUnitDir,
+        BlockReason::SharedPackageFile,
+        BlockReason::DuplicateDefinition,
+        BlockReason::CaseCollision,
+        BlockReason::RecursiveReference,
+        BlockReason::Other,
+    ];
+
+    // The reason for a diagnostic returned by `migrate::analyse`. Every code is listed, such that
+    // a new way for `analyse` to refuse an attribute can't be mistaken for it being migratable
+    pub fn of(diagnostic: &Diagnostic) -> Option<BlockReason> {
+        match diagnostic.code {
+            Code::OutgoingReference => Some(BlockReason::EscapingReference),
+            Code::IncomingReference => Some(BlockReason::ExternalReferencer),
+            Code::UnsupportedName => Some(BlockReason::UnsupportedName),
+            Code::ExistingUnitDir => Some(BlockReason::ExistingUnitDir),
+            Code::SharedPackageFile => Some(BlockReason::SharedPackageFile),
+            Code::DuplicateDefinition => Some(BlockReason::DuplicateDefinition),
+            Code::CaseCollision => Some(BlockReason::CaseCollision),
+            // Not returned by `analyse`
+            Code::NonExistentReference
+            | Code::IgnoredReference
+            | Code::AbsoluteReference
+            | Code::InterleavedParentReference
+            | Code::RootEscapingReference
+            | Code::SearchPathReference
+            | Code::InterpolatedReference
+            | Code::UnparseableFile
+            | Code::UnsupportedAttribute
+            | Code::Migratable
+            | Code::MovedSibling
+            | Code::Whitespace
+            | Code::UnreadableFile
+            | Code::MigrationFailed
+            | Code::Converted
+            | Code::Batch
+            | Code::UnitBoundary
+            | Code::StaleBaselineEntry
+            | Code::ShadowedDefinition
+            | Code::LeftoverFile
+            | Code::UnusedAllowlistEntry => None,
+        }
+    }
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BlockReason::NotCallPackage => "not-call-package",
+            BlockReason::NonLiteralPath => "non-literal-path",
+            BlockReason::InvalidPath => "invalid-path",
+            BlockReason::NonEmptyArguments => "non-empty-arguments",
+            BlockReason::AttributePath => "attribute-path",
+            BlockReason::NonIdentifier => "non-identifier",
+            BlockReason::EscapingReference => "escaping-reference",
+            BlockReason::ExternalReferencer => "external-referencer",
+            BlockReason::UnsupportedName => "unsupported-name",
+            BlockReason::ExistingUnitDir => "existing-unit-dir",
+            BlockReason::SharedPackageFile => "shared-package-file",
+            BlockReason::DuplicateDefinition => "duplicate-definition",
+            BlockReason::CaseCollision => "case-collision",
+            BlockReason::RecursiveReference => "recursive-reference",
+            BlockReason::Other => "other",
+        })
+    }
+}
+
+// A repeated definition of an attribute
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    // The attribute path as written
+    pub attr: String,
+    // Where the attribute is defined first
+    pub first_line: usize,
+    pub first_column: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+// A definition that isn't an entry
+#[derive(Debug, Clone)]
+pub struct Blocked {
+    // The attribute path as written
+    pub attr: String,
+    pub line: usize,
+    pub column: usize,
+    pub reason: BlockReason,
 }
 
 impl AllPackages {
-    pub fn new(path: &PathBuf, global_index: &GlobalIndex) -> AllPackages {
-        let contents = read_to_string(path).unwrap();
+    pub fn new(path: &PathBuf, global_index: &GlobalIndex) -> Result<AllPackages> {
+        let contents = read_to_string(global_index.root.join(path))
+            .with_context(|| format!("Couldn't read file {:?}", path))?;
         let line_index = LineIndex::new(&contents);
         let mut entries = HashMap::new();
+        let mut diagnostics = vec![];
+        let mut blocked = vec![];
+        let mut duplicates = vec![];
+
+        let root = Root::parse(&contents)
+            .ok()
+            .with_context(|| format!("Couldn't parse all-packages.nix file {:?}", path))?;
+
+        let (attribute_set, ignored_sets) = root
+            .expr()
+            .context("The file is empty")
+            .and_then(package_sets)
+            .with_context(|| format!("Couldn't find the package attribute set in {:?}", path))?;
 
-        let root = match Root::parse(&contents).ok() {
-            Ok(root) => root,
-            Err(err) => {
-                eprintln!("Couldn't parse all-packages.nix file {:?}: {:?}", path, err);
-                std::process::exit(1);
+        // Only the first set is analysed, so these can't be migrated
+        for ignored_set in &ignored_sets {
+            for (name, node) in defined_names(ignored_set) {
+                let start = node.text_range().start().into();
+                diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    Code::UnsupportedAttribute,
+                    Location::new(path, line_index.line(start), line_index.column(start)),
+                    format!("Attribute {:?} is defined on the right-hand side of `//`, ignoring it", name),
+                ));
             }
-        };
+        }
 
-        let attribute_set = resulting_attrs(root.expr().unwrap()).unwrap();
+        // The first definition of every attribute path, to find duplicates. Names are compared
+        // as Nix sees them, such that e.g. `"foo" = …` and `inherit foo;` define `foo` as well
+        let mut first_definitions: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut duplicate_nodes = HashSet::new();
+        for (name, node) in defined_names(&attribute_set) {
+            let start = node.text_range().start().into();
+            let (line, column) = (line_index.line(start), line_index.column(start));
+            let Some(&(first_line, first_column)) = first_definitions.get(&name) else {
+                first_definitions.insert(name, (line, column));
+                continue;
+            };
+            duplicates.push(Duplicate {
+                attr: name.clone(),
+                first_line,
+                first_column,
+                line,
+                column,
+            });
+            blocked.push(Blocked {
+                attr: name,
+                line,
+                column,
+                reason: BlockReason::DuplicateDefinition,
+            });
+            duplicate_nodes.insert(node);
+        }
 
         for attribute_definition in attribute_set.attrpath_values() {
-            let line = line_index.line(attribute_definition.syntax().text_range().start().into());
+            if duplicate_nodes.contains(attribute_definition.syntax()) {
+                continue;
+            }
+            let start = attribute_definition.syntax().text_range().start().into();
+            let line = line_index.line(start);
+            let column = line_index.column(start);
+            let end = attribute_definition.syntax().text_range().end().into();
+            let end_line = line_index.line(end);
             let index = attribute_definition.syntax().index();
+            let written = attribute_definition
+                .attrpath()
+                .map_or_else(String::new, |p| p.syntax().to_string());
+            let mut block = |reason| {
+                blocked.push(Blocked {
+                    attr: written.clone(),
+                    line,
+                    column,
+                    reason,
+                })
+            };
             let attribute = {
                 let attribute_path = attribute_definition.attrpath().unwrap();
                 let mut iterator = attribute_path.attrs();
                 let first = iterator.next().unwrap();
-                if let Some(_) = iterator.next() {
-                    eprintln!("Warning: all-packages.nix attribute {:?} defined on line {:?} is an attribute path, ignoring", attribute_path.syntax().to_string(), line);
+                if iterator.next().is_some() {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        Code::UnsupportedAttribute,
+                        Location::new(path, line, column),
+                        format!("Attribute {:?} is an attribute path, ignoring it", attribute_path.syntax().to_string()),
+                    ));
+                    block(BlockReason::AttributePath);
                     continue;
                 }
                 match first {
                     Attr::Ident(it) => it.ident_token().unwrap().text().to_string(),
                     _ => {
-                        eprintln!("Warning: all-packages.nix attribute {:?} defined on line {:?} is not an identifier, ignoring", attribute_path.syntax().to_string(), line);
+                        diagnostics.push(Diagnostic::new(
+                            Severity::Warning,
+                            Code::UnsupportedAttribute,
+                            Location::new(path, line, column),
+                            format!("Attribute {:?} is not an identifier, ignoring it", attribute_path.syntax().to_string()),
+                        ));
+                        block(BlockReason::NonIdentifier);
                         continue;
                     }
                 }
@@ -72,52 +310,151 @@ impl AllPackages {
                 [Expr::Ident(ident_expr), Expr::Path(path_expr), Expr::AttrSet(args_expr)] => {
                     let ident_text = ident_expr.ident_token().unwrap().text().to_string();
                     if ident_text != "callPackage" {
+                        block(BlockReason::NotCallPackage);
                         continue;
                     }
                     let path = {
                         let mut iterator = path_expr.parts();
                         let part = iterator.next().unwrap();
-                        if let Some(_) = iterator.next() {
+                        if iterator.next().is_some() {
+                            block(BlockReason::NonLiteralPath);
                             continue;
                         }
                         let x = match part {
                             InterpolPart::Literal(path) => path.syntax().text().to_string(),
-                            _ => continue,
+                            _ => {
+                                block(BlockReason::NonLiteralPath);
+                                continue;
+                            }
                         };
-                        if let Some((_rel_to_source, _movable_ancestor, rel_to_root)) =
+                        // Invalid references are already recorded by the global index
+                        if let Ok((_rel_to_source, _movable_ancestor, rel_to_root)) =
                             resolve_reference(
-                                &PathBuf::from("./pkgs/top-level/all-packages.nix"),
-                                line,
+                                &global_index.root,
+                                path,
                                 &PathBuf::from(&x),
                                 &global_index.path_indices,
                             )
                         {
                             rel_to_root
                         } else {
+                            block(BlockReason::InvalidPath);
                             continue;
                         }
                     };
 
-                    if let Some(_) = args_expr.entries().next() {
+                    if args_expr.entries().next().is_some() {
+                        block(BlockReason::NonEmptyArguments);
                         continue;
                     }
 
-                    entries.insert(attribute, Entry { index, line, path })
+                    let (comments, indices) = attached_comments(attribute_definition.syntax());
+                    entries.insert(
+                        attribute,
+                        Entry {
+                            index,
+                            line,
+                            column,
+                            end_line,
+                            range: start..end,
+                            path,
+                            comments,
+                            indices,
+                        },
+                    )
+                }
+                [Expr::Ident(ident_expr), path_expr, _]
+                    if ident_expr.ident_token().unwrap().text() == "callPackage" =>
+                {
+                    // E.g. `callPackage (./foo + "/bar") { }` or `callPackage ./foo args`
+                    block(if matches!(path_expr, Expr::Path(_)) {
+                        BlockReason::NonEmptyArguments
+                    } else {
+                        BlockReason::NonLiteralPath
+                    });
+                    continue;
+                }
+                _ => {
+                    block(BlockReason::NotCallPackage);
+                    continue;
                 }
-                _ => continue,
             };
         }
 
-        AllPackages {
+        // Removing a definition from a `rec` set would break its uses by name in the set
+        if attribute_set.rec_token().is_some() {
+            let used = used_names(&attribute_set);
+            let mut referenced: Vec<_> = entries
+                .keys()
+                .filter(|attr| used.contains(*attr))
+                .cloned()
+                .collect();
+            referenced.sort_by_key(|attr| entries[attr].index);
+            for attr in referenced {
+                let entry = entries.remove(&attr).unwrap();
+                diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    Code::UnsupportedAttribute,
+                    Location::new(path, entry.line, entry.column),
+                    format!("Attribute {:?} is used by name within the `rec` attribute set, ignoring it", attr),
+                ));
+                blocked.push(Blocked {
+                    attr,
+                    line: entry.line,
+                    column: entry.column,
+                    reason: BlockReason::RecursiveReference,
+                });
+            }
+        }
+
+        // Neither definition can be migrated, since it's unclear which one is meant
+        for duplicate in &duplicates {
+            if entries.remove(&duplicate.attr).is_some() {
+                blocked.push(Blocked {
+                    attr: duplicate.attr.clone(),
+                    line: duplicate.first_line,
+                    column: duplicate.first_column,
+                    reason: BlockReason::DuplicateDefinition,
+                });
+            }
+        }
+        blocked.sort_by_key(|blocked| blocked.line);
+
+        Ok(AllPackages {
+            root: global_index.root.clone(),
             path: path.to_owned(),
             syntax_node: attribute_set.syntax().to_owned(),
             attributes_to_remove: vec![],
+            paths_to_rewrite: vec![],
+            entry_offsets: entries
+                .iter()
+                .map(|(attr, entry)| (entry.range.start, attr.clone()))
+                .collect(),
+            by_path: entries_by_path(&entries),
+            by_folded_name: entries_by_folded_name(&entries),
             entries,
-        }
+            diagnostics,
+            blocked,
+            duplicates,
+        })
+    }
+
+    // All entries in the order they're defined in
+    pub fn entries_in_order(&self) -> Vec<(&String, &Entry)> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.index);
+        entries
+    }
+
+    // The entry whose definition contains the byte offset, e.g. of a reference
+    pub fn entry_at(&self, offset: usize) -> Option<(&String, &Entry)> {
+        let (_, attr) = self.entry_offsets.range(..=offset).next_back()?;
+        let entry = &self.entries[attr];
+        entry.range.contains(&offset).then_some((attr, entry))
     }
 
     pub fn remove(&mut self, attribute: &String) -> bool {
-        if let Some(_) = self.entries.get(attribute) {
+        if self.entries.contains_key(attribute) {
             self.attributes_to_remove.push(attribute.to_owned());
             // self.entries.remove(attribute);
             true
@@ -126,65 +463,196 @@ impl AllPackages {
         }
     }
 
-    pub fn render(&self) {
+    pub fn duplicate_diagnostics(&self, severity: Severity) -> Vec<Diagnostic> {
+        self.duplicates
+            .iter()
+            .map(|duplicate| {
+                Diagnostic::new(
+                    severity,
+                    Code::DuplicateDefinition,
+                    Location::new(&self.path, duplicate.line, duplicate.column),
+                    format!(
+                        "Attribute {:?} is defined on both line {} and line {}, it won't be migrated",
+                        duplicate.attr, duplicate.first_line, duplicate.line
+                    ),
+                )
+                .with_related(
+                    Location::new(&self.path, duplicate.first_line, duplicate.first_column),
+                    "First defined here".to_string(),
+                )
+            })
+            .collect()
+    }
+
+    // The attributes defined with the same package file as this one, including itself, in
+    // definition order
+    pub fn sharing(&self, attribute: &str) -> &[String] {
+        &self.by_path[&self.entries[attribute].path]
+    }
+
+    // The attributes defined before this one whose names only differ by case
+    pub fn case_variants(&self, attribute: &str) -> &[String] {
+        let variants = &self.by_folded_name[&attribute.to_lowercase()];
+        let position = variants.iter().position(|attr| attr == attribute).unwrap();
+        &variants[..position]
+    }
+
+    // The attribute that is migrated for a package file shared by multiple attributes, the
+    // others are rewritten to refer to its unit directory. It's the one named like the package
+    // directory, or otherwise the first one
+    pub fn canonical(&self, attribute: &str) -> &String {
+        let sharing = self.sharing(attribute);
+        let dir_name = self.entries[attribute]
+            .path
+            .parent()
+            .and_then(|dir| dir.file_name());
+        sharing
+            .iter()
+            .find(|attr| dir_name.is_some_and(|name| name == attr.as_str()))
+            .unwrap_or(&sharing[0])
+    }
+
+    // Replaces the package path of an attribute with another reference
+    pub fn rewrite(&mut self, attribute: &str, reference: &str) {
+        self.paths_to_rewrite.push((attribute.to_owned(), reference.to_owned()));
+    }
+
+    // Undoes the removal or rewrite of an attribute
+    pub fn restore(&mut self, attribute: &str) {
+        self.attributes_to_remove.retain(|attr| attr != attribute);
+        self.paths_to_rewrite.retain(|(attr, _)| attr != attribute);
+    }
+
+    // Writes the file with all removed attributes, returning diagnostics about problems encountered
+    pub fn render(&self) -> Result<Vec<Diagnostic>> {
+        let (contents, diagnostics) = self.rendered()?;
+        self.write(&contents)?;
+        Ok(diagnostics)
+    }
+
+    pub fn write(&self, contents: &str) -> Result<()> {
+        let mut file = File::create(self.root.join(&self.path))
+            .with_context(|| format!("Couldn't open {:?} for writing", self.path))?;
+        file.write_all(contents.as_bytes())
+            .with_context(|| format!("Couldn't write {:?}", self.path))
+    }
+
+    // Whether the attribute set defines this attribute in any way, not just with callPackage
+    pub fn defines(&self, attribute: &str) -> bool {
+        definitions(&self.attribute_set())
+            .iter()
+            .any(|(name, _)| name.as_deref() == Some(attribute))
+    }
+
+    fn attribute_set(&self) -> AttrSet {
+        AttrSet::cast(self.syntax_node.clone()).unwrap()
+    }
+
+    // The contents of the file with a new `callPackage` definition inserted before the first
+    // identifier that sorts after it, ignoring case. Removed attributes aren't taken into account
+    pub fn with_inserted(&self, attribute: &str, reference: &str) -> Result<String> {
+        let definition = format!("{} = callPackage {} {{ }};", attribute, reference);
+        let parsed = Root::parse(&format!("{{\n  {}\n}}", definition)).tree();
+        let node = parsed
+            .expr()
+            .and_then(|expr| resulting_attrs(expr).ok())
+            .and_then(|set| set.attrpath_values().next())
+            .with_context(|| format!("Couldn't create definition {:?}", definition))?
+            .syntax()
+            .green()
+            .into_owned();
+
+        let values: Vec<_> = self.attribute_set().attrpath_values().collect();
+        let next = values.iter().find(|value| {
+            let name = value.attrpath().map(|p| p.syntax().to_string());
+            name.is_some_and(|name| name.to_lowercase() > attribute.to_lowercase())
+        });
+        let anchor = next
+            .or(values.last())
+            .context("Couldn't find any definition to insert the new one next to")?
+            .syntax();
+        // Inserted before the comments attached to the next definition, or after the ones of
+        // the last one, such that they stay with their definition
+        let (_, indices) = attached_comments(anchor);
+        let first = self.syntax_node.children_with_tokens().nth(*indices.start()).unwrap();
+        // Spaced and indented like the definition it's inserted next to
+        let spacing = match first.prev_sibling_or_token() {
+            Some(Token(token))
+                if token.kind() == SyntaxKind::TOKEN_WHITESPACE && token.text().contains('\n') =>
+            {
+                token.text().to_string()
+            }
+            _ => "\n  ".to_string(),
+        };
+        let whitespace = Token(GreenToken::new(
+            NixLanguage::kind_to_raw(SyntaxKind::TOKEN_WHITESPACE),
+            &spacing,
+        ));
         let mut green = self.syntax_node.green().into_owned();
+        let mut expected = definitions(&self.attribute_set());
+        let new = (Some(attribute.to_string()), definition);
+        if next.is_some() {
+            let index = *indices.start();
+            green = green.splice_children(index..index, [NodeOrToken::Node(node), whitespace]);
+            let position = expected.iter().position(|d| d.1 == anchor.to_string()).unwrap();
+            expected.insert(position, new);
+        } else {
+            let index = *indices.end() + 1;
+            green = green.splice_children(index..index, [whitespace, NodeOrToken::Node(node)]);
+            expected.push(new);
+        }
+
+        let contents = self.syntax_node.replace_with(green).to_string();
+        validate(&contents, &expected)
+            .with_context(|| format!("Adding {:?} to {:?} would break it", attribute, self.path))?;
+        Ok(contents)
+    }
+
+    // The contents of the file as it was read
+    pub fn original(&self) -> String {
+        // The attribute set is nested in the file's root node
+        self.syntax_node.ancestors().last().unwrap().to_string()
+    }
+
+    // The contents of the file with all removed attributes, without writing it. Fails if the
+    // result isn't exactly the original with the removed definitions taken out
+    pub fn rendered(&self) -> Result<(String, Vec<Diagnostic>)> {
+        let mut diagnostics = vec![];
+        let mut green = self.syntax_node.green().into_owned();
+        // Rewrites don't change the number of children, so they're done first
+        for (attr, reference) in &self.paths_to_rewrite {
+            let node = SyntaxNode::new_root(green.clone());
+            let path = node
+                .children_with_tokens()
+                .nth(self.entries[attr].index)
+                .and_then(|definition| definition.into_node())
+                .and_then(|definition| {
+                    definition
+                        .descendants()
+                        .find(|node| node.kind() == SyntaxKind::NODE_PATH)
+                })
+                .with_context(|| format!("Couldn't find the package path of {:?}", attr))?;
+            let new_path = Root::parse(reference)
+                .tree()
+                .expr()
+                .filter(|expr| matches!(expr, Expr::Path(_)))
+                .with_context(|| format!("{:?} isn't a path", reference))?;
+            green = path.replace_with(new_path.syntax().green().into_owned());
+        }
+        let rewritten = definitions(&AttrSet::cast(SyntaxNode::new_root(green.clone())).unwrap());
         let mut sorted_indices_to_remove: Vec<(usize, String)> = vec![];
         for attr in self.attributes_to_remove.iter() {
             sorted_indices_to_remove.push((self.entries.get(attr).unwrap().index, attr.to_owned()));
         }
         sorted_indices_to_remove.sort_by_key(|(index, _)| Reverse(*index));
-        for (index, attr) in sorted_indices_to_remove.iter() {
-            let mut potential_comment = false;
-            // Go back until the previous node is found
-            //
-            let mut previous_offset = 1;
-            while let Some(Token(previous)) = green.children().nth(index - previous_offset) {
-                match NixLanguage::kind_from_raw(previous.kind()) {
-                    SyntaxKind::TOKEN_WHITESPACE => {
-                        if previous.text().contains("\n") {
-                            break;
-                        } else {
-                            previous_offset += 1;
-                        }
-                    }
-                    SyntaxKind::TOKEN_COMMENT => {
-                        potential_comment = true;
-                        previous_offset += 1;
-                    }
-                    _ => break,
-                }
-            }
-            let mut next_offset = 1;
-            while let Some(Token(next)) = green.children().nth(index + next_offset) {
-                match NixLanguage::kind_from_raw(next.kind()) {
-                    SyntaxKind::TOKEN_WHITESPACE => {
-                        if next.text().contains("\n") {
-                            break;
-                        } else {
-                            next_offset += 1;
-                        }
-                    }
-                    SyntaxKind::TOKEN_COMMENT => {
-                        potential_comment = true;
-                        next_offset += 1;
-                    }
-                    _ => break,
-                }
-            }
-
-            if potential_comment {
-                green = green.replace_child(
-                    *index,
-                    Token(GreenToken::new(
-                        NixLanguage::kind_to_raw(SyntaxKind::TOKEN_COMMENT),
-                        &format!("/* {} = <moved> */", attr),
-                    )),
-                );
-                continue;
-            }
-            if let Some(Token(previous)) = green.children().nth(index - previous_offset) {
+        for (_, attr) in sorted_indices_to_remove.iter() {
+            // The definition is removed together with its attached comments, which are moved
+            // into the package file
+            let indices = &self.entries.get(attr).unwrap().indices;
+            let (first, last) = (*indices.start(), *indices.end());
+            if let Some(Token(previous)) = green.children().nth(first - 1) {
                 if NixLanguage::kind_from_raw(previous.kind()) == SyntaxKind::TOKEN_WHITESPACE {
-                    if let Some(Token(next)) = green.children().nth(index + next_offset) {
+                    if let Some(Token(next)) = green.children().nth(last + 1) {
                         if NixLanguage::kind_from_raw(next.kind()) == SyntaxKind::TOKEN_WHITESPACE {
                             // if Language::kind_from_raw(next.kind()) == SyntaxKind::TOKEN_WHITESPACE {
                             let mut prev_iter = previous.text().chars().rev().peekable();
@@ -216,7 +684,7 @@ impl AllPackages {
                             new += &x;
 
                             green = green.splice_children(
-                                index - previous_offset..=index + next_offset,
+                                first - 1..=last + 1,
                                 [Token(GreenToken::new(previous.kind(), &new))],
                             );
                             continue;
@@ -224,23 +692,185 @@ impl AllPackages {
                     }
                 }
             }
-            eprintln!(
-                "Couldn't properly strip space around {:?}",
-                green.children().nth(*index).unwrap().to_string()
-            );
-            green = green.remove_child(*index);
+            let entry = self.entries.get(attr).unwrap();
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                Code::Whitespace,
+                Location::new(&self.path, entry.line, entry.column),
+                format!(
+                    "Couldn't properly strip space around {:?}",
+                    green.children().nth(entry.index).unwrap().to_string()
+                ),
+            ));
+            green = green.splice_children(first..=last, []);
         }
-        let mut file = File::create(&self.path).unwrap();
-        file.write(
-            &self
-                .syntax_node
-                .replace_with(green)
-                .to_string()
-                .into_bytes(),
-        )
-        .unwrap();
         // println!("{:#?}", SyntaxNode::new_root(self.syntax_node.replace_with(green)));
+        let contents = self.syntax_node.replace_with(green).to_string();
+        let removed: HashSet<&str> = self.attributes_to_remove.iter().map(String::as_str).collect();
+        let expected: Vec<_> = rewritten
+            .into_iter()
+            .filter(|(name, _)| !name.as_deref().is_some_and(|name| removed.contains(name)))
+            .collect();
+        validate(&contents, &expected)
+            .with_context(|| format!("Changing definitions in {:?} would break it", self.path))?;
+        Ok((contents, diagnostics))
+    }
+}
+
+fn entries_by_path(entries: &HashMap<String, Entry>) -> HashMap<PathBuf, Vec<String>> {
+    let mut in_order: Vec<_> = entries.iter().collect();
+    in_order.sort_by_key(|(_, entry)| entry.index);
+    let mut by_path: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    for (attr, entry) in in_order {
+        by_path.entry(entry.path.clone()).or_default().push(attr.clone());
+    }
+    by_path
+}
+
+fn entries_by_folded_name(entries: &HashMap<String, Entry>) -> HashMap<String, Vec<String>> {
+    let mut in_order: Vec<_> = entries.iter().collect();
+    in_order.sort_by_key(|(_, entry)| entry.index);
+    let mut by_folded_name: HashMap<String, Vec<String>> = HashMap::new();
+    for (attr, _) in in_order {
+        by_folded_name.entry(attr.to_lowercase()).or_default().push(attr.clone());
     }
+    by_folded_name
+}
+
+// The attribute path of every definition as Nix sees it, with the node defining it. Each name of
+// an `inherit` is a definition of its own
+fn defined_names(attribute_set: &AttrSet) -> Vec<(String, SyntaxNode)> {
+    attribute_set
+        .entries()
+        .flat_map(|entry| match entry {
+            rnix::ast::Entry::AttrpathValue(it) => {
+                let name = it.attrpath().map_or_else(String::new, |path| {
+                    path.attrs().map(|attr| attr_name(&attr)).collect::<Vec<_>>().join(".")
+                });
+                vec![(name, it.syntax().clone())]
+            }
+            rnix::ast::Entry::Inherit(it) => it
+                .attrs()
+                .map(|attr| (attr_name(&attr), attr.syntax().clone()))
+                .collect(),
+        })
+        .collect()
+}
+
+// The name an attribute evaluates to, if it's constant, e.g. `foo` for `"foo"` and `${"foo"}`.
+// Otherwise it's the text as written
+fn attr_name(attr: &Attr) -> String {
+    let literal = |string: &rnix::ast::Str| {
+        string
+            .normalized_parts()
+            .into_iter()
+            .map(|part| match part {
+                InterpolPart::Literal(literal) => Some(literal),
+                InterpolPart::Interpolation(_) => None,
+            })
+            .collect::<Option<String>>()
+    };
+    let name = match attr {
+        Attr::Ident(it) => it.ident_token().map(|token| token.text().to_string()),
+        Attr::Str(it) => literal(it),
+        Attr::Dynamic(it) => match it.expr() {
+            Some(Expr::Str(string)) => literal(&string),
+            _ => None,
+        },
+    };
+    name.unwrap_or_else(|| attr.syntax().to_string())
+}
+
+// Checks that rendered contents parse, and contain exactly the expected definitions with
+// unchanged text, in the same order
+fn validate(contents: &str, expected: &[(Option<String>, String)]) -> Result<()> {
+    let parsed = Root::parse(contents);
+    if let Some(error) = parsed.errors().first() {
+        bail!("The result doesn't parse: {}", error);
+    }
+    let attribute_set = parsed
+        .tree()
+        .expr()
+        .context("The result is empty")
+        .and_then(resulting_attrs)
+        .context("The result doesn't contain the package attribute set anymore")?;
+    let actual = definitions(&attribute_set);
+    if let Some((name, text)) = expected.iter().find(|d| !actual.contains(d)) {
+        bail!(
+            "Definition {:?} is missing or changed",
+            name.as_deref().unwrap_or(text)
+        );
+    }
+    if let Some((name, text)) = actual.iter().find(|d| !expected.contains(d)) {
+        bail!(
+            "Definition {:?} is unexpected",
+            name.as_deref().unwrap_or(text)
+        );
+    }
+    if expected != actual {
+        bail!("The order of the definitions changed");
+    }
+    Ok(())
+}
+
+// The name, if it has one, and text of every definition in an attribute set
+fn definitions(attribute_set: &AttrSet) -> Vec<(Option<String>, String)> {
+    attribute_set
+        .entries()
+        .map(|entry| {
+            let name = match &entry {
+                rnix::ast::Entry::AttrpathValue(it) => it.attrpath().map(|p| p.syntax().to_string()),
+                rnix::ast::Entry::Inherit(_) => None,
+            };
+            (name, entry.syntax().to_string())
+        })
+        .collect()
+}
+
+// Finds the comments belonging to a definition: the ones on the lines directly above it without
+// an empty line in between, and one following it on the same line. Returns them in order,
+// together with the range of child indices spanning the definition and these comments
+fn attached_comments(node: &SyntaxNode) -> (Vec<String>, RangeInclusive<usize>) {
+    let is = |element: &Option<SyntaxElement>, kind: SyntaxKind| {
+        matches!(element, Some(Token(token)) if token.kind() == kind)
+    };
+    let newlines = |element: &Option<SyntaxElement>| match element {
+        Some(Token(token)) if token.kind() == SyntaxKind::TOKEN_WHITESPACE => {
+            Some(token.text().matches('\n').count())
+        }
+        _ => None,
+    };
+
+    let mut comments = vec![];
+    let mut first = node.index();
+    let mut whitespace = node.prev_sibling_or_token();
+    while newlines(&whitespace) == Some(1) {
+        let comment = whitespace.as_ref().unwrap().prev_sibling_or_token();
+        // Comments following something else on their line belong to that instead
+        let before = comment.as_ref().and_then(|c| c.prev_sibling_or_token());
+        if !is(&comment, SyntaxKind::TOKEN_COMMENT) || newlines(&before).unwrap_or(0) == 0 {
+            break;
+        }
+        let comment = comment.unwrap();
+        comments.push(comment.to_string());
+        first = comment.index();
+        whitespace = before;
+    }
+    comments.reverse();
+
+    let mut last = node.index();
+    let mut next = node.next_sibling_or_token();
+    if newlines(&next) == Some(0) {
+        next = next.unwrap().next_sibling_or_token();
+    }
+    if is(&next, SyntaxKind::TOKEN_COMMENT) {
+        let comment = next.unwrap();
+        if newlines(&comment.next_sibling_or_token()).unwrap_or(0) > 0 {
+            comments.push(comment.to_string());
+            last = comment.index();
+        }
+    }
+    (comments, first..=last)
 }
 
 fn unwrap_apply_chain(expr: Expr) -> Vec<Expr> {
@@ -254,11 +884,367 @@ fn unwrap_apply_chain(expr: Expr) -> Vec<Expr> {
     }
 }
 
-fn resulting_attrs(expr: Expr) -> Option<AttrSet> {
-    match expr {
-        Expr::Lambda(it) => resulting_attrs(it.body()?),
-        Expr::With(it) => resulting_attrs(it.body()?),
-        Expr::AttrSet(it) => Some(it),
-        _ => None,
+// Finds the attribute set with the package definitions in the expression of the file. It can be
+// wrapped in functions like `{ lib, pkgs }:` or `self: super:`, `with`, `let … in`, `assert` and
+// parentheses, and be `rec`. For `a // b`, the set of `a` is used if it has one, otherwise the
+// one of `b`
+fn resulting_attrs(expr: Expr) -> Result<AttrSet> {
+    package_sets(expr).map(|(attribute_set, _)| attribute_set)
+}
+
+// The attribute set with the package definitions, and the ones merged into it with `//` that
+// aren't analysed, in the order they're written
+fn package_sets(expr: Expr) -> Result<(AttrSet, Vec<AttrSet>)> {
+    let incomplete = || anyhow!("Incomplete expression at {}", position(expr.syntax()));
+    match &expr {
+        Expr::Lambda(it) => package_sets(it.body().ok_or_else(incomplete)?),
+        Expr::With(it) => package_sets(it.body().ok_or_else(incomplete)?),
+        Expr::LetIn(it) => package_sets(it.body().ok_or_else(incomplete)?),
+        Expr::Assert(it) => package_sets(it.body().ok_or_else(incomplete)?),
+        Expr::Paren(it) => package_sets(it.expr().ok_or_else(incomplete)?),
+        Expr::BinOp(it) if it.operator() == Some(BinOpKind::Update) => {
+            let rhs = it.rhs().ok_or_else(incomplete)?;
+            match package_sets(it.lhs().ok_or_else(incomplete)?) {
+                Ok((attribute_set, mut ignored)) => {
+                    if let Ok((other, others)) = package_sets(rhs) {
+                        ignored.push(other);
+                        ignored.extend(others);
+                    }
+                    Ok((attribute_set, ignored))
+                }
+                Err(_) => package_sets(rhs),
+            }
+        }
+        Expr::AttrSet(it) => Ok((it.clone(), vec![])),
+        other => {
+            let text = other.syntax().to_string();
+            let start: String = text.chars().take(40).collect();
+            bail!(
+                "Expected an attribute set, possibly in a function, `with`, `let … in`, `assert` or `//`, but found {:?}{} at {}",
+                start,
+                if start.len() < text.len() { "…" } else { "" },
+                position(other.syntax())
+            )
+        }
+    }
+}
+
+// The line and column a node starts at, for errors about the expression of a file
+fn position(node: &SyntaxNode) -> String {
+    let root = node.ancestors().last().unwrap().to_string();
+    let line_index = LineIndex::new(&root);
+    let start = node.text_range().start().into();
+    format!("line {}, column {}", line_index.line(start), line_index.column(start))
+}
+
+// The names that are used as variables within the attribute set, which in a `rec` set can refer
+// to its own attributes. Shadowing by `let` or function arguments isn't taken into account
+fn used_names(attribute_set: &AttrSet) -> HashSet<String> {
+    attribute_set
+        .syntax()
+        .descendants()
+        .filter(|node| node.kind() == SyntaxKind::NODE_IDENT)
+        .filter(|node| match node.parent() {
+            // Attribute names and function arguments aren't uses
+            Some(parent) if matches!(
+                parent.kind(),
+                SyntaxKind::NODE_ATTRPATH | SyntaxKind::NODE_IDENT_PARAM | SyntaxKind::NODE_PAT_ENTRY
+            ) => false,
+            // `inherit (x) foo` selects from `x`, and a plain `inherit foo` in the set itself
+            // refers to the scope around it
+            Some(parent) if parent.kind() == SyntaxKind::NODE_INHERIT => {
+                let inherit = rnix::ast::Inherit::cast(parent).unwrap();
+                inherit.from().is_none()
+                    && inherit.syntax().parent().as_ref() != Some(attribute_set.syntax())
+            }
+            _ => true,
+        })
+        .map(|node| node.text().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    fn attribute_set(contents: &str) -> AttrSet {
+        resulting_attrs(Root::parse(contents).tree().expr().unwrap()).unwrap()
+    }
+
+    // The comments attached to every definition, and the text spanned by their indices
+    fn comments(contents: &str) -> Vec<(Vec<String>, String)> {
+        let set = attribute_set(contents);
+        set.attrpath_values()
+            .map(|value| {
+                let (comments, indices) = attached_comments(value.syntax());
+                let text: String = set
+                    .syntax()
+                    .children_with_tokens()
+                    .skip(*indices.start())
+                    .take(indices.end() - indices.start() + 1)
+                    .map(|element| element.to_string())
+                    .collect();
+                (comments, text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn comments_above_and_after() {
+        let contents = "{\n  # About foo\n  # More about foo\n  foo = 1; # trailing\n}\n";
+        assert_eq!(
+            comments(contents),
+            [(
+                vec![
+                    "# About foo".to_string(),
+                    "# More about foo".to_string(),
+                    "# trailing".to_string()
+                ],
+                "# About foo\n  # More about foo\n  foo = 1; # trailing".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn comments_separated_by_empty_line() {
+        let contents = "{\n  # Section\n\n  # About foo\n  foo = 1;\n}\n";
+        assert_eq!(
+            comments(contents),
+            [(
+                vec!["# About foo".to_string()],
+                "# About foo\n  foo = 1;".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn comments_of_neighbours() {
+        // The trailing comment of `foo` doesn't belong to `bar`, the one before `baz` on its
+        // line doesn't belong to `baz`
+        let contents = "{\n  foo = 1; # foo\n  bar = 2;\n  baz = 3; /* baz */ qux = 4;\n}\n";
+        assert_eq!(
+            comments(contents),
+            [
+                (vec!["# foo".to_string()], "foo = 1; # foo".to_string()),
+                (vec![], "bar = 2;".to_string()),
+                (vec![], "baz = 3;".to_string()),
+                (vec![], "qux = 4;".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_comments() {
+        assert_eq!(
+            comments("{ foo = 1; }"),
+            [(vec![], "foo = 1;".to_string())]
+        );
+    }
+
+    const ALL_PACKAGES: &str = "pkgs/top-level/all-packages.nix";
+
+    fn project(all_packages: &str) -> TempProject {
+        TempProject::new(&[
+            (ALL_PACKAGES, all_packages),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+            ("pkgs/unit/ba/bar/pkg-fun.nix", "{ }: { }\n"),
+        ])
+    }
+
+    fn all_packages(project: &TempProject) -> AllPackages {
+        AllPackages::new(&PathBuf::from(format!("./{}", ALL_PACKAGES)), &project.index()).unwrap()
+    }
+
+    #[test]
+    fn rendered_removal() {
+        let project = project(
+            "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n\n  # About bar\n  bar = callPackage ../tools/bar { }; # trailing\n  baz = 1;\n}\n",
+        );
+        let mut ap = all_packages(&project);
+        assert!(ap.remove(&"bar".to_string()));
+        let (contents, diagnostics) = ap.rendered().unwrap();
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            contents,
+            "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n\n  baz = 1;\n}\n"
+        );
+        ap.restore("bar");
+        assert_eq!(ap.rendered().unwrap().0, ap.original());
+    }
+
+    #[test]
+    fn rendered_rewrite() {
+        let project = project(
+            "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  bar = callPackage ../tools/bar { };\n}\n",
+        );
+        let mut ap = all_packages(&project);
+        ap.rewrite("bar", "../unit/ba/bar/pkg-fun.nix");
+        assert!(ap.remove(&"foo".to_string()));
+        assert_eq!(
+            ap.rendered().unwrap().0,
+            "{ callPackage }:\n{\n  bar = callPackage ../unit/ba/bar/pkg-fun.nix { };\n}\n"
+        );
+    }
+
+    #[test]
+    fn validate_definitions() {
+        let contents = "{\n  foo = 1;\n  inherit bar;\n  baz = 2;\n}\n";
+        let expected = definitions(&attribute_set(contents));
+        assert!(validate(contents, &expected).is_ok());
+
+        let error = |contents: &str| validate(contents, &expected).unwrap_err().to_string();
+        assert_eq!(error("{\n  foo = 1;\n"), "The result doesn't parse: unexpected end of file");
+        assert_eq!(
+            error("{\n  foo = 1;\n  inherit bar;\n  baz = 3;\n}\n"),
+            "Definition \"baz\" is missing or changed"
+        );
+        assert_eq!(
+            error("{\n  foo = 1;\n  inherit bar;\n  baz = 2;\n  qux = 3;\n}\n"),
+            "Definition \"qux\" is unexpected"
+        );
+        assert_eq!(
+            error("{\n  baz = 2;\n  foo = 1;\n  inherit bar;\n}\n"),
+            "The order of the definitions changed"
+        );
+        assert_eq!(
+            error("42\n"),
+            "The result doesn't contain the package attribute set anymore"
+        );
+    }
+
+    #[test]
+    fn inserted_before_attached_comments() {
+        let project = project(
+            "{ callPackage }:\n{\n  # About foo\n  foo = callPackage ../tools/foo { };\n}\n",
+        );
+        let ap = all_packages(&project);
+        assert_eq!(
+            ap.with_inserted("bar", "../tools/bar").unwrap(),
+            "{ callPackage }:\n{\n  bar = callPackage ../tools/bar { };\n  # About foo\n  foo = callPackage ../tools/foo { };\n}\n"
+        );
+    }
+
+    #[test]
+    fn inserted_after_trailing_comment() {
+        let project = project(
+            "{ callPackage }:\n{\n  bar = callPackage ../tools/bar { }; # About bar\n}\n",
+        );
+        let ap = all_packages(&project);
+        assert_eq!(
+            ap.with_inserted("foo", "../tools/foo").unwrap(),
+            "{ callPackage }:\n{\n  bar = callPackage ../tools/bar { }; # About bar\n  foo = callPackage ../tools/foo { };\n}\n"
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let project = project(
+            "{ callPackage, x }:\n{\n  foo = callPackage ../tools/foo { };\n  \"foo\" = 1;\n  inherit (x) bar;\n  bar = callPackage ../tools/bar { };\n  a.b = 1;\n  a.c = 2;\n  ${\"a\"}.b = 3;\n}\n",
+        );
+        let ap = all_packages(&project);
+        let duplicates: Vec<_> = ap
+            .duplicate_diagnostics(Severity::Error)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        assert_eq!(
+            duplicates,
+            [
+                "Attribute \"foo\" is defined on both line 3 and line 4, it won't be migrated",
+                "Attribute \"bar\" is defined on both line 5 and line 6, it won't be migrated",
+                "Attribute \"a.b\" is defined on both line 7 and line 9, it won't be migrated",
+            ]
+        );
+        assert!(ap.entries.is_empty());
+        let blocked: Vec<_> = ap
+            .blocked
+            .iter()
+            .filter(|blocked| blocked.reason == BlockReason::DuplicateDefinition)
+            .map(|blocked| (blocked.attr.as_str(), blocked.line))
+            .collect();
+        assert_eq!(blocked, [("foo", 3), ("foo", 4), ("bar", 6), ("a.b", 9)]);
+    }
+
+    fn shape(contents: &str) -> Result<Vec<String>> {
+        let set = resulting_attrs(Root::parse(contents).tree().expr().unwrap())?;
+        Ok(definitions(&set).into_iter().filter_map(|(name, _)| name).collect())
+    }
+
+    #[test]
+    fn shapes() {
+        for contents in [
+            "{ foo = 1; }",
+            "{ lib }: with lib; { foo = 1; }",
+            "self: super: { foo = 1; }",
+            "{ lib }: let x = 1; in rec { foo = x; }",
+            "{ lib }: assert lib != null; ({ foo = 1; })",
+            "{ foo = 1; } // { bar = 2; }",
+            "import ./x.nix // { foo = 1; }",
+        ] {
+            assert_eq!(shape(contents).unwrap(), ["foo"], "{}", contents);
+        }
+    }
+
+    #[test]
+    fn unsupported_shapes() {
+        assert_eq!(
+            shape("{ lib }:\nimport ./pkgs.nix { inherit lib; }").unwrap_err().to_string(),
+            "Expected an attribute set, possibly in a function, `with`, `let … in`, `assert` or `//`, but found \"import ./pkgs.nix { inherit lib; }\" at line 2, column 1"
+        );
+        // Incomplete expressions only come up in files that don't parse
+        assert_eq!(
+            shape("{ lib }:\n  with lib;").unwrap_err().to_string(),
+            "Incomplete expression at line 2, column 3"
+        );
+    }
+
+    #[test]
+    fn ignored_update_definitions() {
+        let project = project(
+            "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n} // {\n  bar = callPackage ../tools/bar { };\n  inherit (x) baz;\n}\n",
+        );
+        let ap = all_packages(&project);
+        assert_eq!(ap.entries.keys().collect::<Vec<_>>(), ["foo"]);
+        let messages: Vec<_> = ap
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.location.line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (Some(5), "Attribute \"bar\" is defined on the right-hand side of `//`, ignoring it"),
+                (Some(6), "Attribute \"baz\" is defined on the right-hand side of `//`, ignoring it"),
+            ]
+        );
+    }
+
+    #[test]
+    fn recursive_references() {
+        let project = project(
+            "{ callPackage }:\nrec {\n  foo = callPackage ../tools/foo { };\n  bar = callPackage ../tools/bar { };\n  foo-wrapped = wrap foo;\n  inherit qux;\n  baz = x: x.bar;\n  nested = { inherit (x) bar; };\n}\n",
+        );
+        let ap = all_packages(&project);
+        // Attribute selections and names of other sets don't refer to the set's own attributes
+        assert_eq!(ap.entries.keys().collect::<Vec<_>>(), ["bar"]);
+        let blocked: Vec<_> = ap
+            .blocked
+            .iter()
+            .filter(|blocked| blocked.reason == BlockReason::RecursiveReference)
+            .map(|blocked| (blocked.attr.as_str(), blocked.line))
+            .collect();
+        assert_eq!(blocked, [("foo", 3)]);
+        assert_eq!(
+            ap.diagnostics[0].message,
+            "Attribute \"foo\" is used by name within the `rec` attribute set, ignoring it"
+        );
+
+        // Unlike the set's own `inherit`, the one of a nested set can refer to the set
+        let used = used_names(&attribute_set("rec { a = { inherit b; }; inherit c; d = e: e; }"));
+        let mut used: Vec<_> = used.into_iter().collect();
+        used.sort();
+        assert_eq!(used, ["b", "e"]);
     }
 }
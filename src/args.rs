@@ -1,25 +1,205 @@
+use nix_spp::batch::BatchBy;
+use nix_spp::config::Layout;
+use nix_spp::diagnostic::{Code, Format};
+use nix_spp::report::ReportFormat;
 use clap::Parser;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
-#[command(about, long_about = None)]
+#[command(about, long_about = None, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Mode to run in
-    #[arg(short, long, value_enum)]
-    pub mode: Mode,
+    #[arg(short, long, value_enum, required = true)]
+    pub mode: Option<Mode>,
 
     /// Enable debugging
-    #[arg(short, long, action = clap::ArgAction::Count)]
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
     pub debug: u8,
 
+    /// File listing invalid path references that fail neither `--mode check-references`
+    /// nor `--mode error`, in addition to the ones in the configuration file
+    #[arg(long)]
+    pub allowlist: Option<PathBuf>,
+
+    /// File listing findings that `--mode error` tolerates, such that it only fails on new ones
+    #[arg(long, value_name = "FILE")]
+    pub baseline: Option<PathBuf>,
+
+    /// Write all current findings to the `--baseline` file instead of failing on them
+    #[arg(long, requires = "baseline")]
+    pub write_baseline: bool,
+
+    /// Configuration file to use instead of `nix-spp.toml` in the nixpkgs root
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Path of all-packages.nix, relative to the nixpkgs root
+    #[arg(long, global = true)]
+    pub all_packages: Option<PathBuf>,
+
+    /// Directory layout to migrate packages into
+    #[arg(long, value_enum, global = true)]
+    pub layout: Option<Layout>,
+
+    /// Directory to migrate packages into, relative to the nixpkgs root, overrides the layout
+    #[arg(long, global = true)]
+    pub unit_dir: Option<PathBuf>,
+
+    /// Name of the package file in the unit directory, overrides the layout
+    #[arg(long, global = true)]
+    pub package_file: Option<String>,
+
+    /// Number of leading characters of the attribute name used for the shard directory
+    #[arg(long, global = true)]
+    pub shard_length: Option<usize>,
+
+    /// Directory up to which directories left empty by a migration are removed, relative to the
+    /// nixpkgs root
+    #[arg(long, global = true, value_name = "DIR")]
+    pub cleanup_stop: Option<PathBuf>,
+
+    /// Gitignore-style glob of paths not to index, relative to the nixpkgs root
+    /// (can be repeated, adds to the configuration file)
+    #[arg(long, value_name = "GLOB", global = true)]
+    pub exclude: Vec<String>,
+
+    /// How to output diagnostics
+    #[arg(long, value_enum, default_value_t = Format::Text, global = true)]
+    pub format: Format,
+
+    /// Only report diagnostics with this code, e.g. NSPP001 (can be repeated)
+    #[arg(long, value_name = "CODE", global = true)]
+    pub select: Vec<Code>,
+
+    /// Don't report diagnostics with this code, e.g. NSPP001 (can be repeated)
+    #[arg(long, value_name = "CODE", global = true)]
+    pub suppress: Vec<Code>,
+
+    /// Only process attributes matching this glob, or regex if prefixed with `re:`
+    /// (can be repeated)
+    #[arg(long, value_name = "PATTERN")]
+    pub attr: Vec<String>,
+
+    /// Only process attributes matching the patterns in this file, one per line
+    #[arg(long, value_name = "FILE")]
+    pub attrs_from: Option<PathBuf>,
+
+    /// Don't process attributes matching this glob, or regex if prefixed with `re:`
+    /// (can be repeated)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude_attr: Vec<String>,
+
+    /// Stop after this many attributes were found to be migratable
+    #[arg(long, value_name = "N")]
+    pub limit: Option<usize>,
+
+    /// Split the migration into batches that are applied one after the other
+    #[arg(long, value_enum)]
+    pub batch_by: Option<BatchBy>,
+
+    /// Maximum number of moved files per batch, implies `--batch-by size` if not given
+    #[arg(long, value_name = "N")]
+    pub max_files: Option<usize>,
+
+    /// Maximum number of lines removed from all-packages.nix per batch, implies
+    /// `--batch-by size` if not given
+    #[arg(long, value_name = "N")]
+    pub max_lines: Option<usize>,
+
+    /// Print a patch to apply with `git apply` instead of changing any files
+    #[arg(long, conflicts_with = "git", global = true)]
+    pub diff: bool,
+
+    /// Move files with `git mv` and commit the migration to the local git repository
+    #[arg(long, global = true)]
+    pub git: bool,
+
+    /// With `--git`, whether to make a commit per migrated attribute or per batch
+    #[arg(long, value_enum, default_value_t = CommitPer::Attribute)]
+    pub commit_per: CommitPer,
+
+    /// With `--git`, add the migration commits to .git-blame-ignore-revs in a final commit
+    #[arg(long, requires = "git")]
+    pub blame_ignore_revs: bool,
+
     /// The path to nixpkgs
-    pub path: PathBuf,
+    #[arg(required = true)]
+    pub path: Option<PathBuf>,
+}
+
+impl Args {
+    // The path to nixpkgs, which subcommands take themselves
+    pub fn path(&self) -> &Path {
+        match &self.command {
+            Some(
+                Command::Unmigrate { path, .. }
+                | Command::Lsp { path }
+                | Command::Report { path, .. }
+                | Command::Explain { path, .. },
+            ) => path,
+            None => self.path.as_ref().unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Move a package from the unit directory back to a directory of its own and define it in
+    /// all-packages.nix again
+    Unmigrate {
+        /// The attribute to move back
+        attr: String,
+
+        /// Directory to move the package to, relative to the nixpkgs root
+        #[arg(long, value_name = "DIR")]
+        to: PathBuf,
+
+        /// The path to nixpkgs
+        path: PathBuf,
+    },
+    /// Print why each all-packages.nix attribute can't be migrated, with counts per reason
+    Report {
+        /// What to print
+        #[arg(long, value_enum, default_value_t = ReportFormat::Csv)]
+        output: ReportFormat,
+
+        /// The path to nixpkgs
+        path: PathBuf,
+    },
+    /// Explain how the migration of an attribute was decided: the files it transitively
+    /// references, which other files are moved along, and the references blocking it
+    Explain {
+        /// The attribute to explain
+        attr: String,
+
+        /// The path to nixpkgs
+        path: PathBuf,
+    },
+    /// Run a language server on stdin and stdout, showing diagnostics while editing and
+    /// navigating path references
+    Lsp {
+        /// The path to nixpkgs
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CommitPer {
+    Attribute,
+    Batch,
 }
 
-#[derive(Debug, Clone, clap::ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 pub enum Mode {
     // Can be removed once the migration is done
     Migrate,
     Warn,
     Error,
+    /// Only check that all path references point to existing files within nixpkgs
+    CheckReferences,
+    /// Move packages from an existing pkgs/unit tree into the selected layout
+    ConvertLayout,
 }
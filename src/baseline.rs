@@ -0,0 +1,240 @@
+use crate::boundary::BoundaryViolation;
+use crate::config::unprefixed;
+use crate::index::InvalidReference;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+
+// A finding that can be tolerated by the baseline. It's keyed by attribute or file and reference
+// text instead of the line, such that unrelated edits don't invalidate it
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Finding {
+    Migratable(String),
+    InvalidReference { file: PathBuf, reference: String },
+    UnitBoundary { file: PathBuf, reference: String },
+}
+
+impl Finding {
+    pub fn invalid_reference(invalid: &InvalidReference) -> Finding {
+        Finding::InvalidReference {
+            file: unprefixed(&invalid.source).to_path_buf(),
+            reference: invalid.text.clone(),
+        }
+    }
+
+    pub fn unit_boundary(violation: &BoundaryViolation) -> Finding {
+        Finding::UnitBoundary {
+            file: unprefixed(&violation.source).to_path_buf(),
+            reference: violation.text.clone(),
+        }
+    }
+
+    fn parse(line: &str) -> Option<Finding> {
+        let (kind, rest) = line.split_once('\t')?;
+        let file_and_reference = || {
+            let (file, reference) = rest.split_once('\t')?;
+            if file.is_empty() || reference.is_empty() || reference.contains('\t') {
+                return None;
+            }
+            Some((unprefixed(Path::new(file)).to_path_buf(), reference.to_string()))
+        };
+        match kind {
+            "migratable" if !rest.is_empty() && !rest.contains('\t') => {
+                Some(Finding::Migratable(rest.to_string()))
+            }
+            "reference" => {
+                let (file, reference) = file_and_reference()?;
+                Some(Finding::InvalidReference { file, reference })
+            }
+            "boundary" => {
+                let (file, reference) = file_and_reference()?;
+                Some(Finding::UnitBoundary { file, reference })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Migratable(attr) => write!(f, "migratable\t{}", attr),
+            Finding::InvalidReference { file, reference } => {
+                write!(f, "reference\t{}\t{}", file.display(), reference)
+            }
+            Finding::UnitBoundary { file, reference } => {
+                write!(f, "boundary\t{}\t{}", file.display(), reference)
+            }
+        }
+    }
+}
+
+// Findings that are tolerated for now, such that `--mode error` only fails on new ones.
+// Each non-empty line of the baseline file has one of the forms
+//
+//   migratable<TAB><attr>
+//   reference<TAB><file><TAB><reference>
+//   boundary<TAB><file><TAB><reference>
+//
+// where <file> is relative to the project root. The fields are separated by tabs, since file
+// names can contain spaces. Lines starting with `#` are comments
+#[derive(Debug, Default)]
+pub struct Baseline {
+    entries: BTreeSet<Finding>,
+    // Whether everything is tolerated, for writing a new baseline
+    all: bool,
+    // All findings checked against the baseline so far
+    seen: BTreeSet<Finding>,
+}
+
+impl Baseline {
+    // A baseline tolerating everything, to record the findings for a new one
+    pub fn tolerate_all() -> Baseline {
+        Baseline {
+            all: true,
+            ..Baseline::default()
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Baseline> {
+        let contents = read_to_string(path)
+            .with_context(|| format!("Couldn't read baseline file {:?}", path))?;
+        Baseline::parse(&contents, path)
+    }
+
+    fn parse(contents: &str, path: &Path) -> Result<Baseline> {
+        let mut entries = BTreeSet::new();
+        for (number, line) in contents.lines().enumerate() {
+            // Only line endings are stripped, the tabs between fields need to stay
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Finding::parse(line) {
+                Some(finding) => {
+                    entries.insert(finding);
+                }
+                None => bail!(
+                    "Baseline file {:?} line {} should have the form `migratable<TAB><attr>`, `reference<TAB><file><TAB><reference>` or `boundary<TAB><file><TAB><reference>`: {:?}",
+                    path,
+                    number + 1,
+                    line
+                ),
+            }
+        }
+        Ok(Baseline {
+            entries,
+            ..Baseline::default()
+        })
+    }
+
+    // Whether the finding is tolerated, remembering that it was seen
+    pub fn contains(&mut self, finding: Finding) -> bool {
+        let contained = self.all || self.entries.contains(&finding);
+        self.seen.insert(finding);
+        contained
+    }
+
+    // Entries that weren't seen, which should be removed from the baseline. Migratable attributes
+    // are only included with `migratable`, since otherwise not all of them were checked
+    pub fn stale(&self, migratable: bool) -> impl Iterator<Item = &Finding> + '_ {
+        self.entries
+            .difference(&self.seen)
+            .filter(move |finding| migratable || !matches!(finding, Finding::Migratable(_)))
+    }
+
+    // Writes all seen findings as the new baseline
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut contents = String::from(
+            "# Findings tolerated by `--mode error`, generated with `--write-baseline`\n",
+        );
+        for finding in &self.seen {
+            contents += &format!("{}\n", finding);
+        }
+        write(path, contents).with_context(|| format!("Couldn't write baseline file {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Baseline> {
+        Baseline::parse(contents, Path::new("baseline"))
+    }
+
+    fn reference(file: &str, reference: &str) -> Finding {
+        Finding::InvalidReference {
+            file: PathBuf::from(file),
+            reference: reference.to_string(),
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        let baseline = parse(
+            "# Comment\n\nmigratable\tfoo\nreference\t./pkgs/my tools/default.nix\t../missing\r\nboundary\tpkgs/unit/fo/foo/pkg-fun.nix\t../../ba/bar\n",
+        )
+        .unwrap();
+        assert_eq!(
+            baseline.entries.into_iter().collect::<Vec<_>>(),
+            [
+                Finding::Migratable("foo".to_string()),
+                reference("pkgs/my tools/default.nix", "../missing"),
+                Finding::UnitBoundary {
+                    file: PathBuf::from("pkgs/unit/fo/foo/pkg-fun.nix"),
+                    reference: "../../ba/bar".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_lines() {
+        for line in [
+            "migratable foo",
+            "migratable\t",
+            "reference\tpkgs/foo.nix",
+            "reference\tpkgs/foo.nix\t../a\t../b",
+            "unknown\tfoo",
+        ] {
+            let error = parse(&format!("# Comment\n{}\n", line)).unwrap_err().to_string();
+            assert!(error.contains("line 2 should have the form"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn written_findings_parse_again() {
+        let findings = [
+            Finding::Migratable("foo".to_string()),
+            reference("pkgs/my tools/default.nix", "./a b.patch"),
+        ];
+        for finding in findings {
+            let parsed = parse(&finding.to_string()).unwrap();
+            assert_eq!(parsed.entries.into_iter().collect::<Vec<_>>(), [finding]);
+        }
+    }
+
+    #[test]
+    fn staleness() {
+        let mut baseline =
+            parse("migratable\tfoo\nmigratable\tbar\nreference\tpkgs/a.nix\t./b\nreference\tpkgs/c.nix\t./d\n")
+                .unwrap();
+        assert!(baseline.contains(Finding::Migratable("foo".to_string())));
+        assert!(baseline.contains(reference("pkgs/a.nix", "./b")));
+        assert!(!baseline.contains(reference("pkgs/e.nix", "./f")));
+        assert_eq!(
+            baseline.stale(true).collect::<Vec<_>>(),
+            [
+                &Finding::Migratable("bar".to_string()),
+                &reference("pkgs/c.nix", "./d")
+            ]
+        );
+        assert_eq!(
+            baseline.stale(false).collect::<Vec<_>>(),
+            [&reference("pkgs/c.nix", "./d")]
+        );
+    }
+}
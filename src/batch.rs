@@ -0,0 +1,206 @@
+use crate::migrate::Migration;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BatchBy {
+    /// One batch per shard directory, split further if it exceeds the size limits
+    Shard,
+    /// Consecutive attributes up to the size limits
+    Size,
+}
+
+// Limits on how big a single batch may get. A single migration exceeding them gets its own batch
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchLimits {
+    // Number of moved files
+    pub max_files: Option<usize>,
+    // Number of lines removed from all-packages.nix
+    pub max_lines: Option<usize>,
+}
+
+// A group of migrations that can be applied and reviewed independently of all others
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub name: String,
+    pub migrations: Vec<Migration>,
+}
+
+impl Batch {
+    fn new(name: String) -> Batch {
+        Batch {
+            name,
+            migrations: vec![],
+        }
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.migrations.iter().map(|m| m.moves.len()).sum()
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.migrations.iter().map(|m| m.lines).sum()
+    }
+
+    fn fits(&self, migration: &Migration, limits: &BatchLimits) -> bool {
+        self.migrations.is_empty()
+            || (limits
+                .max_files
+                .is_none_or(|max| self.file_count() + migration.moves.len() <= max)
+                && limits
+                    .max_lines
+                    .is_none_or(|max| self.line_count() + migration.lines <= max))
+    }
+}
+
+// Splits migrations into batches, keeping the order of the migrations within each batch
+pub fn batches(
+    migrations: Vec<Migration>,
+    by: Option<BatchBy>,
+    limits: &BatchLimits,
+) -> Vec<Batch> {
+    match by {
+        None => {
+            let mut batch = Batch::new("all".to_string());
+            batch.migrations = migrations;
+            vec![batch]
+        }
+        Some(BatchBy::Size) => split(migrations, "size", limits),
+        Some(BatchBy::Shard) => {
+            let mut shards: BTreeMap<OsString, Vec<Migration>> = BTreeMap::new();
+            for migration in migrations {
+                let shard = migration.unit_dir.parent().unwrap().file_name().unwrap();
+                shards.entry(shard.to_owned()).or_default().push(migration);
+            }
+            shards
+                .into_iter()
+                .flat_map(|(shard, migrations)| {
+                    split(
+                        migrations,
+                        &format!("shard {}", shard.to_string_lossy()),
+                        limits,
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+fn split(migrations: Vec<Migration>, name: &str, limits: &BatchLimits) -> Vec<Batch> {
+    let mut result: Vec<Batch> = vec![];
+    let mut current = Batch::new(name.to_string());
+    for migration in migrations {
+        if !current.fits(&migration, limits) {
+            result.push(current);
+            current = Batch::new(name.to_string());
+        }
+        current.migrations.push(migration);
+    }
+    if !current.migrations.is_empty() {
+        result.push(current);
+    }
+    // Disambiguate split batches
+    if result.len() > 1 {
+        let count = result.len();
+        for (index, batch) in result.iter_mut().enumerate() {
+            batch.name = format!("{}, part {} of {}", batch.name, index + 1, count);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Location;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    fn migration(attr: &str, files: usize, lines: usize) -> Migration {
+        let unit_dir = PathBuf::from(format!("./pkgs/unit/{}/{}", &attr[..2], attr));
+        Migration {
+            attr: attr.to_string(),
+            location: Location::file(&PathBuf::from("./pkgs/top-level/all-packages.nix")),
+            path: PathBuf::from(format!("./pkgs/tools/{}/default.nix", attr)),
+            lines,
+            moves: (0..files)
+                .map(|i| {
+                    (
+                        PathBuf::from(format!("./pkgs/tools/{}/{}.nix", attr, i)),
+                        unit_dir.join(format!("{}.nix", i)),
+                    )
+                })
+                .collect(),
+            unit_dir,
+            closure: HashSet::new(),
+            comments: vec![],
+            unowned: vec![],
+            aliases: vec![],
+            diagnostics: vec![],
+        }
+    }
+
+    fn attrs(batches: &[Batch]) -> Vec<(String, Vec<&str>)> {
+        batches
+            .iter()
+            .map(|batch| {
+                let attrs = batch.migrations.iter().map(|m| m.attr.as_str()).collect();
+                (batch.name.clone(), attrs)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_batching() {
+        let migrations = vec![migration("foo", 1, 1), migration("bar", 1, 1)];
+        let batches = batches(migrations, None, &BatchLimits::default());
+        assert_eq!(attrs(&batches), [("all".to_string(), vec!["foo", "bar"])]);
+    }
+
+    #[test]
+    fn size_limits() {
+        let migrations = vec![
+            migration("aa", 2, 1),
+            migration("ab", 2, 1),
+            migration("ac", 5, 1),
+            migration("ad", 1, 3),
+        ];
+        let limits = BatchLimits {
+            max_files: Some(4),
+            max_lines: Some(3),
+        };
+        let batches = batches(migrations, Some(BatchBy::Size), &limits);
+        assert_eq!(
+            attrs(&batches),
+            [
+                ("size, part 1 of 3".to_string(), vec!["aa", "ab"]),
+                // Exceeding the limits on its own still gets a batch
+                ("size, part 2 of 3".to_string(), vec!["ac"]),
+                ("size, part 3 of 3".to_string(), vec!["ad"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn by_shard() {
+        let migrations = vec![
+            migration("foo", 1, 1),
+            migration("bar", 1, 1),
+            migration("fox", 1, 1),
+            migration("fob", 1, 1),
+        ];
+        let limits = BatchLimits {
+            max_files: Some(2),
+            max_lines: None,
+        };
+        let batches = batches(migrations, Some(BatchBy::Shard), &limits);
+        assert_eq!(
+            attrs(&batches),
+            [
+                ("shard ba".to_string(), vec!["bar"]),
+                ("shard fo, part 1 of 2".to_string(), vec!["foo", "fox"]),
+                ("shard fo, part 2 of 2".to_string(), vec!["fob"]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::Config;
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
+use crate::index::GlobalIndex;
+use std::path::{Path, PathBuf};
+
+// The package directory `<unit dir>/<shard>/<name>` a path is in, if any
+pub fn package_dir(config: &Config, path: &Path) -> Option<PathBuf> {
+    let unit_dir = config.unit_dir();
+    let depth = unit_dir.components().count();
+    if !path.starts_with(&unit_dir) || path.components().count() < depth + 2 {
+        return None;
+    }
+    Some(path.components().take(depth + 2).collect())
+}
+
+// A path reference crossing the directory of a package in the unit directory
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct BoundaryViolation {
+    // The file containing the reference, relative to the project root
+    pub source: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    // The package directory the reference leaves, or otherwise refers into
+    pub package_dir: PathBuf,
+    pub leaves: bool,
+}
+
+impl BoundaryViolation {
+    pub fn diagnostic(&self, severity: Severity) -> Diagnostic {
+        let description = if self.leaves {
+            "leaves the package directory"
+        } else {
+            "refers into the package directory"
+        };
+        Diagnostic::new(
+            severity,
+            Code::UnitBoundary,
+            Location::new(&self.source, self.line, self.column),
+            format!("Path reference {:?} {} {:?}", self.text, description, self.package_dir),
+        )
+    }
+}
+
+// Finds references violating the self-containedness of packages in the unit directory: files of a
+// package directory may only reference files in the same directory, and no other file may
+// reference into it. The exception is all-packages.nix referring to a package file, e.g. to
+// `callPackage` it with custom arguments or to define an alias
+pub fn unit_boundary_violations(index: &GlobalIndex, config: &Config) -> Vec<BoundaryViolation> {
+    let all_packages = config.all_packages_path();
+    let mut violations = vec![];
+    for (path, path_index) in &index.path_indices {
+        let source = package_dir(config, path);
+        for reference in &path_index.references {
+            let target = package_dir(config, &reference.rel_to_root);
+            if source == target {
+                continue;
+            }
+            if path == &all_packages
+                && target.as_ref().is_some_and(|target| {
+                    reference.rel_to_root == target.join(config.package_file())
+                })
+            {
+                continue;
+            }
+            violations.push(BoundaryViolation {
+                source: path.clone(),
+                line: reference.line,
+                column: reference.column,
+                text: reference.text.clone(),
+                leaves: source.is_some(),
+                package_dir: source.clone().or(target).unwrap(),
+            });
+        }
+    }
+    violations.sort_by(|a, b| (&a.source, a.line, a.column).cmp(&(&b.source, b.line, b.column)));
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::all_packages::AllPackages;
+    use crate::migrate::{self, FileSystem};
+    use crate::test_util::TempProject;
+
+    fn violations(project: &TempProject) -> Vec<String> {
+        unit_boundary_violations(&project.index(), &Config::default())
+            .iter()
+            .map(|violation| violation.diagnostic(Severity::Error).message)
+            .collect()
+    }
+
+    #[test]
+    fn references_into_package_dirs() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo-custom = callPackage ../unit/fo/foo/pkg-fun.nix { withX = true; };\n  foo-data = ../unit/fo/foo/data.json;\n}\n",
+            ),
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { src = ../../ba/bar/src; }\n"),
+            ("pkgs/unit/fo/foo/data.json", ""),
+            ("pkgs/unit/ba/bar/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/ba/bar/src/main.c", ""),
+            ("pkgs/tools/baz/default.nix", "{ }: { p = ../../unit/fo/foo/pkg-fun.nix; }\n"),
+        ]);
+        assert_eq!(
+            violations(&project),
+            [
+                "Path reference \"../../unit/fo/foo/pkg-fun.nix\" refers into the package directory \"./pkgs/unit/fo/foo\"",
+                "Path reference \"../unit/fo/foo/data.json\" refers into the package directory \"./pkgs/unit/fo/foo\"",
+                "Path reference \"../../ba/bar/src\" leaves the package directory \"./pkgs/unit/fo/foo\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn migrated_aliases() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  foo-alias = callPackage ../tools/foo { };\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+        ]);
+        let index = project.index();
+        let config = Config::default();
+        let mut all_packages = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        let migration = migrate::analyse(&index, &all_packages, &config, "foo").unwrap();
+        let mover = FileSystem {
+            root: project.root.clone(),
+        };
+        migrate::apply(&mover, &migration).unwrap();
+        all_packages.remove(&migration.attr);
+        for (alias, reference) in &migration.aliases {
+            all_packages.rewrite(alias, reference);
+        }
+        all_packages.render().unwrap();
+
+        assert_eq!(
+            project.read("pkgs/top-level/all-packages.nix"),
+            "{ callPackage }:\n{\n  foo-alias = callPackage ../unit/fo/foo/pkg-fun.nix { };\n}\n"
+        );
+        assert_eq!(violations(&project), Vec::<String>::new());
+    }
+}
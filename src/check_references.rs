@@ -0,0 +1,198 @@
+use crate::config::unprefixed;
+use crate::diagnostic::{Code, Diagnostic, Location, Reporter, Severity};
+use crate::index::{GlobalIndex, InvalidReference};
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+// A set of known invalid references that shouldn't fail the check.
+// Each non-empty line of the allowlist file has the form
+//
+//   <file> <reference>
+//
+// where <file> is relative to the project root, e.g.
+//
+//   pkgs/development/foo/default.nix ../bar/patches/fix.patch
+//
+// Lines starting with `#` are comments. Entries are keyed by the reference text instead of the
+// line, such that unrelated edits to the file don't invalidate them.
+#[derive(Debug, Default)]
+pub struct Allowlist {
+    entries: HashSet<(PathBuf, String)>,
+}
+
+impl Allowlist {
+    pub fn load(path: &Path) -> Result<Allowlist> {
+        let contents = read_to_string(path)
+            .with_context(|| format!("Couldn't read allowlist file {:?}", path))?;
+        let mut entries = HashSet::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((file, reference)) => {
+                    entries.insert((unprefixed(Path::new(file)).to_path_buf(), reference.trim().to_string()));
+                }
+                None => bail!(
+                    "Allowlist file {:?} line {} should have the form `<file> <reference>`: {:?}",
+                    path,
+                    number + 1,
+                    line
+                ),
+            }
+        }
+        Ok(Allowlist { entries })
+    }
+
+    pub fn insert(&mut self, file: &Path, reference: &str) {
+        self.entries.insert((unprefixed(file).to_path_buf(), reference.to_string()));
+    }
+
+    fn key(invalid: &InvalidReference) -> (PathBuf, String) {
+        (unprefixed(&invalid.source).to_path_buf(), invalid.text.clone())
+    }
+
+    pub fn contains(&self, invalid: &InvalidReference) -> bool {
+        self.entries.contains(&Allowlist::key(invalid))
+    }
+
+    // Warnings for the entries that don't match any invalid reference anymore
+    pub fn unused(&self, index: &GlobalIndex) -> Vec<Diagnostic> {
+        let used: HashSet<_> = index
+            .invalid_references
+            .iter()
+            .filter(|invalid| invalid.kind.is_error())
+            .map(Allowlist::key)
+            .collect();
+        let mut unused: Vec<_> = self.entries.difference(&used).collect();
+        unused.sort();
+        unused
+            .into_iter()
+            .map(|(file, reference)| {
+                Diagnostic::new(
+                    Severity::Warning,
+                    Code::UnusedAllowlistEntry,
+                    Location::file(file),
+                    format!("Allowlisted reference {:?} doesn't occur anymore, remove it from the allowlist", reference),
+                )
+            })
+            .collect()
+    }
+}
+
+// The invalid references in the index that aren't errors or aren't allowlisted, which every mode
+// reports
+pub fn reported<'a>(
+    index: &'a GlobalIndex,
+    allowlist: &'a Allowlist,
+) -> impl Iterator<Item = &'a InvalidReference> + 'a {
+    index
+        .invalid_references
+        .iter()
+        .filter(|invalid| !invalid.kind.is_error() || !allowlist.contains(invalid))
+}
+
+// Reports all invalid references in the index, failing if any of them are errors that aren't
+// allowlisted
+pub fn check_references(
+    index: &GlobalIndex,
+    allowlist: &Allowlist,
+    reporter: &mut Reporter,
+) -> Result<()> {
+    let mut error_count = 0;
+    for invalid in reported(index, allowlist) {
+        if !invalid.kind.is_error() {
+            reporter.report(invalid.diagnostic(Severity::Warning));
+            continue;
+        }
+        if reporter.is_enabled(invalid.kind.code()) {
+            reporter.report(invalid.diagnostic(Severity::Error));
+            error_count += 1;
+        }
+    }
+
+    reporter.report_all(allowlist.unused(index));
+
+    if error_count > 0 {
+        bail!("Found {} invalid path reference(s)", error_count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    #[test]
+    fn allowlisted_references_are_not_reported() {
+        let project = TempProject::new(&[
+            ("foo.nix", "[ ./missing ./gone ]\n"),
+            ("bar.nix", "./missing\n"),
+        ]);
+        let index = project.index();
+        let mut allowlist = Allowlist::default();
+        allowlist.insert(Path::new("./foo.nix"), "./missing");
+        let mut reported: Vec<_> = reported(&index, &allowlist)
+            .map(|invalid| (invalid.source.to_string_lossy().into_owned(), invalid.text.as_str()))
+            .collect();
+        // Files are indexed in no particular order
+        reported.sort();
+        assert_eq!(
+            reported,
+            [
+                ("./bar.nix".to_string(), "./missing"),
+                ("./foo.nix".to_string(), "./gone"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unused_entries() {
+        let project = TempProject::new(&[("foo.nix", "./missing\n")]);
+        let index = project.index();
+        let mut allowlist = Allowlist::default();
+        allowlist.insert(Path::new("./foo.nix"), "./missing");
+        allowlist.insert(Path::new("foo.nix"), "./fixed");
+        let unused: Vec<_> = allowlist
+            .unused(&index)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.location.to_string(), diagnostic.message))
+            .collect();
+        assert_eq!(
+            unused,
+            [(
+                Code::UnusedAllowlistEntry,
+                "foo.nix".to_string(),
+                "Allowlisted reference \"./fixed\" doesn't occur anymore, remove it from the allowlist".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn allowlist_file() {
+        let project = TempProject::new(&[(
+            "allowlist",
+            "# Known broken\n\npkgs/foo/default.nix ../bar/fix.patch\n  ./pkgs/baz.nix   ./with space  \n",
+        )]);
+        let allowlist = Allowlist::load(&project.root.join("allowlist")).unwrap();
+        let mut entries: Vec<_> = allowlist.entries.into_iter().collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                (PathBuf::from("pkgs/baz.nix"), "./with space".to_string()),
+                (PathBuf::from("pkgs/foo/default.nix"), "../bar/fix.patch".to_string()),
+            ]
+        );
+
+        project.write("invalid", "pkgs/foo/default.nix ../bar\npkgs/foo.nix\n");
+        let err = Allowlist::load(&project.root.join("invalid")).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("line 2 should have the form `<file> <reference>`: \"pkgs/foo.nix\""));
+    }
+}
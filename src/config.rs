@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::path::{Component, Path, PathBuf};
+
+// The name of the configuration file, looked up in the project root
+pub const CONFIG_FILE: &str = "nix-spp.toml";
+
+// Project layout and analysis settings, read from `nix-spp.toml`, e.g.
+//
+//   all-packages = "pkgs/top-level/all-packages.nix"
+//   layout = "by-name"
+//   # Override the directory and package file name of the layout
+//   unit-dir = "pkgs/by-name"
+//   package-file = "package.nix"
+//   shard-length = 2
+//   exclude = ["pkgs/test/**"]
+//   # Directories emptied by a migration are removed up to this one
+//   cleanup-stop = "pkgs"
+//
+//   [[allowed-references]]
+//   file = "pkgs/development/foo/default.nix"
+//   reference = "../bar/patches/fix.patch"
+//
+// All paths are relative to the project root. Every setting is optional
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    // The file containing the `callPackage` definitions to migrate
+    pub all_packages: PathBuf,
+    pub layout: Layout,
+    // The directory packages are migrated into, defaults to the one of the layout
+    pub unit_dir: Option<PathBuf>,
+    // What the package file is called in the unit directory, defaults to the one of the layout
+    pub package_file: Option<String>,
+    // How many lowercased leading characters of the attribute name make up the shard directory
+    pub shard_length: usize,
+    // Gitignore-style globs of paths that shouldn't be indexed
+    pub exclude: Vec<String>,
+    // The directory up to which directories left empty by a migration are removed, exclusive
+    pub cleanup_stop: PathBuf,
+    // Invalid references that fail neither `--mode check-references` nor `--mode error`
+    pub allowed_references: Vec<AllowedReference>,
+}
+
+// The directory structure packages are migrated into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// `pkgs/unit/<shard>/<name>/pkg-fun.nix`, from earlier drafts of RFC 140
+    Unit,
+    /// `pkgs/by-name/<shard>/<name>/package.nix`, as accepted in RFC 140
+    ByName,
+}
+
+impl Layout {
+    pub fn unit_dir(&self) -> PathBuf {
+        PathBuf::from(match self {
+            Layout::Unit => "pkgs/unit",
+            Layout::ByName => "pkgs/by-name",
+        })
+    }
+
+    pub fn package_file(&self) -> String {
+        match self {
+            Layout::Unit => "pkg-fun.nix",
+            Layout::ByName => "package.nix",
+        }
+        .to_string()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllowedReference {
+    pub file: PathBuf,
+    pub reference: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            all_packages: PathBuf::from("pkgs/top-level/all-packages.nix"),
+            layout: Layout::Unit,
+            unit_dir: None,
+            package_file: None,
+            shard_length: 2,
+            exclude: vec![],
+            cleanup_stop: PathBuf::from("pkgs"),
+            allowed_references: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = read_to_string(path)
+            .with_context(|| format!("Couldn't read configuration file {:?}", path))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Couldn't parse configuration file {:?}", path))
+    }
+
+    // Loads the configuration file from the project root, or the defaults if there is none
+    pub fn discover(root: &Path) -> Result<Config> {
+        let path = root.join(CONFIG_FILE);
+        if path.exists() {
+            Config::load(&path)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    // The all-packages.nix path in the `./`-prefixed form the index uses
+    pub fn all_packages_path(&self) -> PathBuf {
+        rel_to_root(&self.all_packages)
+    }
+
+    // The directory all packages are migrated into, in the `./`-prefixed form the index uses
+    pub fn unit_dir(&self) -> PathBuf {
+        rel_to_root(
+            &self
+                .unit_dir
+                .clone()
+                .unwrap_or_else(|| self.layout.unit_dir()),
+        )
+    }
+
+    // The cleanup stop directory in the `./`-prefixed form the index uses
+    pub fn cleanup_stop(&self) -> PathBuf {
+        rel_to_root(&self.cleanup_stop)
+    }
+
+    pub fn package_file(&self) -> String {
+        self.package_file
+            .clone()
+            .unwrap_or_else(|| self.layout.package_file())
+    }
+
+    pub fn shard_dir(&self, attr: &str) -> OsString {
+        attr_shard_dir(attr, self.shard_length)
+    }
+
+    // The directory a package for this attribute is migrated into, relative to the root
+    pub fn unit_dir_for(&self, attr: &str) -> PathBuf {
+        self.unit_dir().join(self.shard_dir(attr)).join(attr)
+    }
+}
+
+pub fn attr_shard_dir(attr: &str, length: usize) -> OsString {
+    let str: String = attr.to_lowercase().chars().take(length).collect();
+    str.into()
+}
+
+// Converts a path relative to the root into the `./`-prefixed form the index uses
+pub fn rel_to_root(path: &Path) -> PathBuf {
+    Path::new(".").join(unprefixed(path))
+}
+
+// The path without the `./` prefix the index uses, as users write and read it
+pub fn unprefixed(path: &Path) -> &Path {
+    path.strip_prefix(".").unwrap_or(path)
+}
+
+// A Nix path literal referring to `to` from the directory `from`, both relative to the root
+pub fn relative_reference(from: &Path, to: &Path) -> String {
+    let from: Vec<Component> = from.components().filter(|c| c != &Component::CurDir).collect();
+    let to: Vec<Component> = to.components().filter(|c| c != &Component::CurDir).collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut reference = PathBuf::new();
+    for _ in common..from.len() {
+        reference.push("..");
+    }
+    reference.extend(&to[common..]);
+    if common == from.len() {
+        format!("./{}", reference.display())
+    } else {
+        reference.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::GlobalIndex;
+    use crate::test_util::TempProject;
+
+    #[test]
+    fn discover() {
+        let project = TempProject::new(&[("default.nix", "")]);
+        let config = Config::discover(&project.root).unwrap();
+        assert_eq!(config.unit_dir(), Path::new("./pkgs/unit"));
+        assert_eq!(config.package_file(), "pkg-fun.nix");
+
+        project.write(
+            CONFIG_FILE,
+            "all-packages = \"./top/all.nix\"\nlayout = \"by-name\"\nshard-length = 3\n\n[[allowed-references]]\nfile = \"foo.nix\"\nreference = \"./bar\"\n",
+        );
+        let config = Config::discover(&project.root).unwrap();
+        assert_eq!(config.all_packages_path(), Path::new("./top/all.nix"));
+        assert_eq!(config.unit_dir_for("Foobar"), Path::new("./pkgs/by-name/foo/Foobar"));
+        assert_eq!(config.package_file(), "package.nix");
+        assert_eq!(config.cleanup_stop(), Path::new("./pkgs"));
+        assert_eq!(config.allowed_references.len(), 1);
+        assert_eq!(config.allowed_references[0].reference, "./bar");
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let project = TempProject::new(&[
+            ("typo.toml", "unit_dir = \"pkgs/by-name\"\n"),
+            ("nested.toml", "[[allowed-references]]\nfile = \"foo.nix\"\nreference = \"./bar\"\nline = 3\n"),
+        ]);
+        for file in ["typo.toml", "nested.toml"] {
+            let err = Config::load(&project.root.join(file)).unwrap_err();
+            assert!(format!("{:#}", err).contains("unknown field"), "{:#}", err);
+        }
+        assert!(Config::load(&project.root.join("missing.toml")).is_err());
+    }
+
+    #[test]
+    fn exclude() {
+        let project = TempProject::new(&[
+            ("pkgs/test/foo.nix", "./missing\n"),
+            ("pkgs/tools/foo.nix", ""),
+            ("pkgs/tools/foo.patch", ""),
+        ]);
+        let index = GlobalIndex::new(&project.root, &["pkgs/test/**".to_string(), "*.patch".to_string()]).unwrap();
+        assert!(!index.path_indices.contains_key(Path::new("./pkgs/test/foo.nix")));
+        assert!(!index.path_indices.contains_key(Path::new("./pkgs/tools/foo.patch")));
+        assert!(index.path_indices.contains_key(Path::new("./pkgs/tools/foo.nix")));
+        assert!(index.invalid_references.is_empty());
+
+        let err = GlobalIndex::new(&project.root, &["pkgs/{".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid exclusion \"pkgs/{\"");
+    }
+
+    #[test]
+    fn relative_references() {
+        let reference = |from: &str, to: &str| relative_reference(Path::new(from), Path::new(to));
+        assert_eq!(
+            reference("./pkgs/top-level", "./pkgs/tools/foo"),
+            "../tools/foo"
+        );
+        assert_eq!(
+            reference("./pkgs/top-level", "./pkgs/top-level/foo.nix"),
+            "./foo.nix"
+        );
+        assert_eq!(
+            reference("pkgs/top-level", "./pkgs/unit/fo/foo/pkg-fun.nix"),
+            "../unit/fo/foo/pkg-fun.nix"
+        );
+        assert_eq!(reference("./a/b/c", "./d"), "../../../d");
+    }
+}
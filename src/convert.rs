@@ -0,0 +1,263 @@
+use crate::config::{rel_to_root, Config, Layout};
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
+use crate::index::GlobalIndex;
+use crate::migrate::{move_files, Mover};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Moving a single package from the legacy `pkgs/unit` layout into the configured one
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    pub attr: String,
+    pub from: PathBuf,
+    pub to: PathBuf,
+    // Pairs of old and new paths, relative to the root
+    pub moves: Vec<(PathBuf, PathBuf)>,
+}
+
+// Determines how to move every package in `pkgs/unit/<shard>/<name>` into the layout of the
+// configuration, renaming `pkg-fun.nix` to the configured package file and recomputing the
+// shard. All other files keep their place relative to the package directory. Packages that can't
+// be converted are skipped with a diagnostic. This doesn't change anything on disk
+pub fn analyse(index: &GlobalIndex, config: &Config) -> Result<(Vec<Conversion>, Vec<Diagnostic>)> {
+    let source_dir = rel_to_root(&Layout::Unit.unit_dir());
+    let source_file = Layout::Unit.package_file();
+    if config.unit_dir() == source_dir {
+        bail!(
+            "The configured layout already uses {:?}, select a different one to convert to, e.g. with `--layout by-name`",
+            source_dir
+        );
+    }
+    let depth = source_dir.components().count();
+
+    // All files of each package directory
+    let mut packages: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for (path, path_index) in &index.path_indices {
+        if path_index.is_dir || !path.starts_with(&source_dir) {
+            continue;
+        }
+        // Skips files directly in the unit or shard directory
+        if path.components().count() < depth + 3 {
+            continue;
+        }
+        let package_dir: PathBuf = path.components().take(depth + 2).collect();
+        packages.entry(package_dir).or_default().push(path.clone());
+    }
+
+    let mut conversions = vec![];
+    let mut diagnostics = vec![];
+    'packages: for (from, mut files) in packages {
+        files.sort();
+        let attr = match from.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let to = config.unit_dir_for(&attr);
+
+        if index.path_indices.contains_key(&to) || index.root.join(&to).exists() {
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                Code::ExistingUnitDir,
+                Location::file(&from),
+                format!(
+                    "Cannot convert package {:?}, because {:?} already exists",
+                    attr, to
+                ),
+            ));
+            continue;
+        }
+
+        // Directories count as well, e.g. with `src = ../../fo/foo/src`
+        for path in index.paths_under(&from) {
+            for (referenced_by, reference_index) in &index.path_indices[path].referenced_by {
+                if referenced_by.starts_with(&from) {
+                    continue;
+                }
+                let reference =
+                    &index.path_indices.get(referenced_by).unwrap().references[*reference_index];
+                diagnostics.push(
+                    Diagnostic::new(
+                        Severity::Error,
+                        Code::IncomingReference,
+                        Location::file(&from),
+                        format!("Cannot convert package {:?}, because {:?} is referenced from outside", attr, path),
+                    )
+                    .with_related(
+                        Location::new(referenced_by, reference.line, reference.column),
+                        format!("Path reference {:?} would break", reference.text),
+                    ),
+                );
+                continue 'packages;
+            }
+        }
+        for file in &files {
+            let path_index = index.path_indices.get(file).unwrap();
+            for reference in &path_index.references {
+                if reference.movable_ancestor.starts_with(&from) {
+                    continue;
+                }
+                diagnostics.push(
+                    Diagnostic::new(
+                        Severity::Error,
+                        Code::OutgoingReference,
+                        Location::file(&from),
+                        format!("Cannot convert package {:?}, because it references a path outside its directory", attr),
+                    )
+                    .with_related(
+                        Location::new(file, reference.line, reference.column),
+                        format!("Path reference {:?} would break", reference.text),
+                    ),
+                );
+                continue 'packages;
+            }
+        }
+
+        let moves = files
+            .into_iter()
+            .map(|old| {
+                let base = old.strip_prefix(&from).unwrap();
+                let new = if base == Path::new(&source_file) {
+                    to.join(config.package_file())
+                } else {
+                    to.join(base)
+                };
+                (old, new)
+            })
+            .collect();
+        conversions.push(Conversion {
+            attr,
+            from,
+            to,
+            moves,
+        });
+    }
+    Ok((conversions, diagnostics))
+}
+
+// Moves the files of a conversion. Either all files are moved or none
+pub fn apply(mover: &dyn Mover, conversion: &Conversion) -> Result<()> {
+    move_files(mover, &conversion.moves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate::{remove_empty_dirs, FileSystem};
+    use crate::test_util::TempProject;
+
+    fn by_name() -> Config {
+        Config {
+            layout: Layout::ByName,
+            ..Config::default()
+        }
+    }
+
+    fn analysed(project: &TempProject) -> (Vec<Conversion>, Vec<(Code, String)>) {
+        let (conversions, diagnostics) = analyse(&project.index(), &by_name()).unwrap();
+        let diagnostics = diagnostics
+            .into_iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.message))
+            .collect();
+        (conversions, diagnostics)
+    }
+
+    #[test]
+    fn same_layout() {
+        let project = TempProject::new(&[("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { }\n")]);
+        let err = analyse(&project.index(), &Config::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The configured layout already uses \"./pkgs/unit\", select a different one to convert to, e.g. with `--layout by-name`"
+        );
+    }
+
+    #[test]
+    fn renames_package_file() {
+        let project = TempProject::new(&[
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { patches = [ ./patches/fix.patch ]; }\n"),
+            ("pkgs/unit/fo/foo/patches/fix.patch", ""),
+            ("pkgs/unit/README.md", ""),
+        ]);
+        let (conversions, diagnostics) = analysed(&project);
+        assert_eq!(diagnostics, []);
+        assert_eq!(conversions.len(), 1);
+        assert_eq!(conversions[0].attr, "foo");
+        assert_eq!(conversions[0].to, PathBuf::from("./pkgs/by-name/fo/foo"));
+        assert_eq!(
+            conversions[0].moves,
+            [
+                (
+                    PathBuf::from("./pkgs/unit/fo/foo/patches/fix.patch"),
+                    PathBuf::from("./pkgs/by-name/fo/foo/patches/fix.patch")
+                ),
+                (
+                    PathBuf::from("./pkgs/unit/fo/foo/pkg-fun.nix"),
+                    PathBuf::from("./pkgs/by-name/fo/foo/package.nix")
+                ),
+            ]
+        );
+
+        let mover = FileSystem {
+            root: project.root.clone(),
+        };
+        apply(&mover, &conversions[0]).unwrap();
+        let removed =
+            remove_empty_dirs(&project.root, &conversions[0].moves, &by_name().cleanup_stop()).unwrap();
+        assert_eq!(
+            removed,
+            [
+                PathBuf::from("./pkgs/unit/fo/foo/patches"),
+                PathBuf::from("./pkgs/unit/fo/foo"),
+                PathBuf::from("./pkgs/unit/fo"),
+            ]
+        );
+        assert!(project.exists("pkgs/by-name/fo/foo/package.nix"));
+        assert!(project.exists("pkgs/by-name/fo/foo/patches/fix.patch"));
+        // Not part of any package
+        assert!(project.exists("pkgs/unit/README.md"));
+    }
+
+    #[test]
+    fn existing_target() {
+        let project = TempProject::new(&[
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/by-name/fo/foo/package.nix", "{ }: { }\n"),
+        ]);
+        let (conversions, diagnostics) = analysed(&project);
+        assert!(conversions.is_empty());
+        assert_eq!(
+            diagnostics,
+            [(
+                Code::ExistingUnitDir,
+                "Cannot convert package \"foo\", because \"./pkgs/by-name/fo/foo\" already exists".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn crossing_references() {
+        let project = TempProject::new(&[
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { src = ../../ba/bar/src; }\n"),
+            ("pkgs/unit/ba/bar/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/ba/bar/src/main.c", ""),
+            ("pkgs/unit/ba/baz/pkg-fun.nix", "{ }: { }\n"),
+        ]);
+        let (conversions, diagnostics) = analysed(&project);
+        let converted: Vec<_> = conversions.iter().map(|conversion| conversion.attr.as_str()).collect();
+        assert_eq!(converted, ["baz"]);
+        assert_eq!(
+            diagnostics,
+            [
+                (
+                    Code::IncomingReference,
+                    "Cannot convert package \"bar\", because \"./pkgs/unit/ba/bar\" is referenced from outside".to_string()
+                ),
+                (
+                    Code::OutgoingReference,
+                    "Cannot convert package \"foo\", because it references a path outside its directory".to_string()
+                ),
+            ]
+        );
+    }
+}
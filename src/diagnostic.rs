@@ -0,0 +1,463 @@
+use crate::config::unprefixed;
+use crate::sarif;
+use serde::{Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+// Stable identifiers for every kind of diagnostic, such that they can be selected or suppressed.
+// Codes must never be reused for a different meaning, only new ones may be added
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Code {
+    NonExistentReference,
+    IgnoredReference,
+    AbsoluteReference,
+    InterleavedParentReference,
+    RootEscapingReference,
+    SearchPathReference,
+    InterpolatedReference,
+    UnparseableFile,
+    UnsupportedAttribute,
+    OutgoingReference,
+    IncomingReference,
+    Migratable,
+    MovedSibling,
+    Whitespace,
+    UnreadableFile,
+    MigrationFailed,
+    ExistingUnitDir,
+    Converted,
+    Batch,
+    UnitBoundary,
+    StaleBaselineEntry,
+    CaseCollision,
+    UnsupportedName,
+    ShadowedDefinition,
+    LeftoverFile,
+    SharedPackageFile,
+    DuplicateDefinition,
+    UnusedAllowlistEntry,
+}
+
+impl Code {
+    pub const ALL: [Code; 28] = [
+        Code::NonExistentReference,
+        Code::IgnoredReference,
+        Code::AbsoluteReference,
+        Code::InterleavedParentReference,
+        Code::RootEscapingReference,
+        Code::SearchPathReference,
+        Code::InterpolatedReference,
+        Code::UnparseableFile,
+        Code::UnsupportedAttribute,
+        Code::OutgoingReference,
+        Code::IncomingReference,
+        Code::Migratable,
+        Code::MovedSibling,
+        Code::Whitespace,
+        Code::UnreadableFile,
+        Code::MigrationFailed,
+        Code::ExistingUnitDir,
+        Code::Converted,
+        Code::Batch,
+        Code::UnitBoundary,
+        Code::StaleBaselineEntry,
+        Code::CaseCollision,
+        Code::UnsupportedName,
+        Code::ShadowedDefinition,
+        Code::LeftoverFile,
+        Code::SharedPackageFile,
+        Code::DuplicateDefinition,
+        Code::UnusedAllowlistEntry,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Code::NonExistentReference => "NSPP001",
+            Code::IgnoredReference => "NSPP002",
+            Code::AbsoluteReference => "NSPP003",
+            Code::InterleavedParentReference => "NSPP004",
+            Code::RootEscapingReference => "NSPP005",
+            Code::SearchPathReference => "NSPP006",
+            Code::InterpolatedReference => "NSPP007",
+            Code::UnparseableFile => "NSPP008",
+            Code::UnsupportedAttribute => "NSPP009",
+            Code::OutgoingReference => "NSPP010",
+            Code::IncomingReference => "NSPP011",
+            Code::Migratable => "NSPP012",
+            Code::MovedSibling => "NSPP013",
+            Code::Whitespace => "NSPP014",
+            Code::UnreadableFile => "NSPP015",
+            Code::MigrationFailed => "NSPP016",
+            Code::ExistingUnitDir => "NSPP017",
+            Code::Converted => "NSPP018",
+            Code::Batch => "NSPP019",
+            Code::UnitBoundary => "NSPP020",
+            Code::StaleBaselineEntry => "NSPP021",
+            Code::CaseCollision => "NSPP022",
+            Code::UnsupportedName => "NSPP023",
+            Code::ShadowedDefinition => "NSPP024",
+            Code::LeftoverFile => "NSPP025",
+            Code::SharedPackageFile => "NSPP026",
+            Code::DuplicateDefinition => "NSPP027",
+            Code::UnusedAllowlistEntry => "NSPP028",
+        }
+    }
+
+    // A short description of the category, independent of the specific occurrence
+    pub fn description(&self) -> &'static str {
+        match self {
+            Code::NonExistentReference => "Path reference to a non-existent path",
+            Code::IgnoredReference => "Path reference to a path that is ignored by the index",
+            Code::AbsoluteReference => "Absolute path reference",
+            Code::InterleavedParentReference => "Path reference with an interleaved `..` segment",
+            Code::RootEscapingReference => "Path reference escaping the project root",
+            Code::SearchPathReference => "Path reference to the Nix search path",
+            Code::InterpolatedReference => "Path reference with subexpressions",
+            Code::UnparseableFile => "Nix file that couldn't be parsed",
+            Code::UnsupportedAttribute => "Unsupported attribute definition in all-packages.nix",
+            Code::OutgoingReference => {
+                "Package references a path outside its directory, so it can't be migrated"
+            }
+            Code::IncomingReference => {
+                "Package files are referenced from outside, so it can't be migrated"
+            }
+            Code::Migratable => "Attribute in all-packages.nix that can be migrated",
+            Code::MovedSibling => "Unreferenced file moved along with a package",
+            Code::Whitespace => "Couldn't strip whitespace around a removed attribute",
+            Code::UnreadableFile => "Nix file that couldn't be read",
+            Code::MigrationFailed => "Moving the files of a package failed",
+            Code::ExistingUnitDir => "The unit directory for a package already exists",
+            Code::Converted => "Package moved between directory layouts",
+            Code::Batch => "Group of migrations applied together",
+            Code::UnitBoundary => "Path reference crossing the directory of a package in the unit directory",
+            Code::StaleBaselineEntry => "Baseline entry that isn't found anymore",
+            Code::CaseCollision => "Unit directories that only differ by case",
+            Code::UnsupportedName => "Attribute name not supported for unit directories",
+            Code::ShadowedDefinition => "Attribute defined both in all-packages.nix and the unit directory",
+            Code::LeftoverFile => "File staying behind in the directory of a migrated package",
+            Code::SharedPackageFile => "Package file used by multiple attributes",
+            Code::DuplicateDefinition => "Attribute defined more than once in all-packages.nix",
+            Code::UnusedAllowlistEntry => "Allowlisted reference that doesn't occur anymore",
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.id())
+    }
+}
+
+impl FromStr for Code {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Code, String> {
+        Code::ALL
+            .into_iter()
+            .find(|code| code.id().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown diagnostic code {:?}", s))
+    }
+}
+
+impl Serialize for Code {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.id())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
+pub struct Location {
+    // Relative to the project root
+    #[serde(serialize_with = "serialize_path")]
+    pub file: PathBuf,
+    // 1-based, `None` if the diagnostic is about the file as a whole
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl Location {
+    pub fn file(file: &Path) -> Location {
+        Location {
+            file: file.to_path_buf(),
+            line: None,
+            column: None,
+        }
+    }
+
+    pub fn new(file: &Path, line: usize, column: usize) -> Location {
+        Location {
+            file: file.to_path_buf(),
+            line: Some(line),
+            column: Some(column),
+        }
+    }
+
+    // The file without the `./` prefix used internally, which is what editors and CI expect
+    pub fn display_file(&self) -> String {
+        unprefixed(&self.file).display().to_string()
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.display_file())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&unprefixed(path).display().to_string())
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
+pub struct Related {
+    pub location: Location,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub location: Location,
+    pub message: String,
+    pub related: Vec<Related>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: Code, location: Location, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code,
+            location,
+            message,
+            related: vec![],
+        }
+    }
+
+    pub fn with_related(mut self, location: Location, message: String) -> Diagnostic {
+        self.related.push(Related { location, message });
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Human readable text on stderr
+    Text,
+    /// One JSON object per line on stdout
+    Json,
+    /// GitHub Actions workflow commands on stdout, which show up as code annotations
+    Github,
+    /// A SARIF 2.1.0 log on stdout, written once all diagnostics are known
+    Sarif,
+}
+
+// Filters diagnostics by code and renders them in the selected format as they come in
+pub struct Reporter {
+    format: Format,
+    // If non-empty, only these codes are reported
+    select: HashSet<Code>,
+    suppress: HashSet<Code>,
+    error_count: usize,
+    // For formats that can only be written at the end
+    buffered: Vec<Diagnostic>,
+}
+
+impl Reporter {
+    pub fn new(format: Format, select: &[Code], suppress: &[Code]) -> Reporter {
+        Reporter {
+            format,
+            select: select.iter().copied().collect(),
+            suppress: suppress.iter().copied().collect(),
+            error_count: 0,
+            buffered: vec![],
+        }
+    }
+
+    pub fn is_enabled(&self, code: Code) -> bool {
+        (self.select.is_empty() || self.select.contains(&code)) && !self.suppress.contains(&code)
+    }
+
+    pub fn report(&mut self, diagnostic: Diagnostic) {
+        if !self.is_enabled(diagnostic.code) {
+            return;
+        }
+        if diagnostic.severity == Severity::Error {
+            self.error_count += 1;
+        }
+        match self.format {
+            Format::Text => eprintln!("{}", render_text(&diagnostic)),
+            Format::Json => println!("{}", serde_json::to_string(&diagnostic).unwrap()),
+            Format::Github => println!("{}", render_github(&diagnostic)),
+            Format::Sarif => self.buffered.push(diagnostic),
+        }
+    }
+
+    pub fn report_all(&mut self, diagnostics: impl IntoIterator<Item = Diagnostic>) {
+        for diagnostic in diagnostics {
+            self.report(diagnostic);
+        }
+    }
+
+    // The number of reported (not filtered out) diagnostics with error severity
+    pub fn error_count(&self) -> usize {
+        self.error_count
+    }
+
+    // Writes out buffered diagnostics, needs to be called once at the end
+    pub fn finish(&mut self) {
+        if let Format::Sarif = self.format {
+            let log = sarif::log(&self.buffered);
+            println!("{}", serde_json::to_string_pretty(&log).unwrap());
+            self.buffered.clear();
+        }
+    }
+}
+
+fn render_text(diagnostic: &Diagnostic) -> String {
+    let mut result = format!(
+        "{}[{}]: {}: {}",
+        diagnostic.severity, diagnostic.code, diagnostic.location, diagnostic.message
+    );
+    for related in &diagnostic.related {
+        result += &format!("\n  {}: {}", related.location, related.message);
+    }
+    result
+}
+
+// See https://docs.github.com/en/actions/using-workflows/workflow-commands-for-github-actions
+fn render_github(diagnostic: &Diagnostic) -> String {
+    let command = match diagnostic.severity {
+        Severity::Note => "notice",
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    };
+    let location = &diagnostic.location;
+    let mut properties = format!("file={}", escape_property(&location.display_file()));
+    if let Some(line) = location.line {
+        properties += &format!(",line={}", line);
+    }
+    if let Some(column) = location.column {
+        properties += &format!(",col={}", column);
+    }
+    properties += &format!(",title={}", diagnostic.code);
+
+    let mut message = diagnostic.message.clone();
+    for related in &diagnostic.related {
+        message += &format!("\n{}: {}", related.location, related.message);
+    }
+    format!("::{} {}::{}", command, properties, escape_data(&message))
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(severity: Severity, code: Code) -> Diagnostic {
+        Diagnostic::new(
+            severity,
+            code,
+            Location::new(Path::new("./pkgs/a,b:c.nix"), 3, 5),
+            "Line one\nline two, 100%".to_string(),
+        )
+        .with_related(Location::file(Path::new("./pkgs/top-level/all-packages.nix")), "Defined here".to_string())
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            render_text(&diagnostic(Severity::Error, Code::NonExistentReference)),
+            "error[NSPP001]: pkgs/a,b:c.nix:3:5: Line one\nline two, 100%\n  pkgs/top-level/all-packages.nix: Defined here"
+        );
+        let file_only = Diagnostic::new(
+            Severity::Note,
+            Code::Converted,
+            Location::file(Path::new("./pkgs/unit")),
+            "Moved".to_string(),
+        );
+        assert_eq!(render_text(&file_only), "note[NSPP018]: pkgs/unit: Moved");
+    }
+
+    #[test]
+    fn github() {
+        assert_eq!(
+            render_github(&diagnostic(Severity::Warning, Code::NonExistentReference)),
+            "::warning file=pkgs/a%2Cb%3Ac.nix,line=3,col=5,title=NSPP001::Line one%0Aline two, 100%25%0Apkgs/top-level/all-packages.nix: Defined here"
+        );
+    }
+
+    #[test]
+    fn select_and_suppress() {
+        let mut reporter = Reporter::new(
+            Format::Sarif,
+            &[Code::NonExistentReference, Code::AbsoluteReference],
+            &[Code::AbsoluteReference],
+        );
+        assert!(reporter.is_enabled(Code::NonExistentReference));
+        assert!(!reporter.is_enabled(Code::AbsoluteReference));
+        assert!(!reporter.is_enabled(Code::Migratable));
+        reporter.report_all([
+            diagnostic(Severity::Error, Code::NonExistentReference),
+            diagnostic(Severity::Warning, Code::NonExistentReference),
+            diagnostic(Severity::Error, Code::AbsoluteReference),
+            diagnostic(Severity::Error, Code::Migratable),
+        ]);
+        // Filtered out diagnostics don't count
+        assert_eq!(reporter.error_count(), 1);
+        let codes: Vec<_> = reporter.buffered.iter().map(|d| (d.severity, d.code)).collect();
+        assert_eq!(
+            codes,
+            [
+                (Severity::Error, Code::NonExistentReference),
+                (Severity::Warning, Code::NonExistentReference),
+            ]
+        );
+    }
+
+    #[test]
+    fn suppress_only() {
+        let mut reporter = Reporter::new(Format::Sarif, &[], &[Code::Migratable]);
+        reporter.report(diagnostic(Severity::Error, Code::Migratable));
+        reporter.report(diagnostic(Severity::Error, Code::UnusedAllowlistEntry));
+        reporter.report(diagnostic(Severity::Note, Code::Batch));
+        assert_eq!(reporter.error_count(), 1);
+        assert_eq!(reporter.buffered.len(), 2);
+    }
+}
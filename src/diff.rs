@@ -0,0 +1,179 @@
+// Proposing changes as a patch that can be applied later with `git apply`, instead of
+// changing anything on disk
+use crate::config::unprefixed;
+use crate::migrate::Mover;
+use anyhow::{Context, Result};
+use similar::TextDiff;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// A patch in the extended format of `git diff`, so that renames are represented
+#[derive(Debug, Clone, Default)]
+pub struct Patch {
+    text: String,
+}
+
+impl Patch {
+    // Adds the changes to a file's contents, if any
+    pub fn modify(&mut self, path: &Path, old: &str, new: &str) {
+        if old == new {
+            return;
+        }
+        // Paths in patches are relative to the root
+        let path = unprefixed(path).display();
+        let a = format!("a/{}", path);
+        let b = format!("b/{}", path);
+        self.text += &format!("diff --git {} {}\n", a, b);
+        self.text += &TextDiff::from_lines(old, new)
+            .unified_diff()
+            .context_radius(3)
+            .header(&a, &b)
+            .to_string();
+    }
+
+    // Adds the rename of a file whose contents don't change
+    pub fn rename(&mut self, old: &Path, new: &Path) {
+        self.rename_modified(old, new, "", "");
+    }
+
+    // Adds the rename of a file together with changes to its contents
+    pub fn rename_modified(&mut self, old: &Path, new: &Path, old_contents: &str, new_contents: &str) {
+        let (old, new) = (unprefixed(old).display(), unprefixed(new).display());
+        let diff = TextDiff::from_lines(old_contents, new_contents);
+        self.text += &format!(
+            "diff --git a/{} b/{}\nsimilarity index {}%\nrename from {}\nrename to {}\n",
+            old,
+            new,
+            (diff.ratio() * 100.0) as u32,
+            old,
+            new
+        );
+        if old_contents != new_contents {
+            self.text += &diff
+                .unified_diff()
+                .context_radius(3)
+                .header(&format!("a/{}", old), &format!("b/{}", new))
+                .to_string();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Records moves instead of doing them, such that they can be added to a patch
+#[derive(Debug)]
+pub struct Recorder {
+    root: PathBuf,
+    moves: RefCell<Vec<(PathBuf, PathBuf)>>,
+    // The original and changed contents of moved files by their new path
+    changes: RefCell<HashMap<PathBuf, (String, String)>>,
+}
+
+impl Recorder {
+    pub fn new(root: &Path) -> Recorder {
+        Recorder {
+            root: root.to_path_buf(),
+            moves: RefCell::default(),
+            changes: RefCell::default(),
+        }
+    }
+
+    // Adds all recorded moves to the patch
+    pub fn add_to(&self, patch: &mut Patch) {
+        let changes = self.changes.borrow();
+        for (old, new) in self.moves.borrow().iter() {
+            match changes.get(new) {
+                Some((old_contents, new_contents)) => {
+                    patch.rename_modified(old, new, old_contents, new_contents)
+                }
+                None => patch.rename(old, new),
+            }
+        }
+    }
+}
+
+impl Mover for Recorder {
+    fn move_file(&self, old: &Path, new: &Path) -> Result<()> {
+        let mut moves = self.moves.borrow_mut();
+        // Undoing a recorded move drops it, instead of recording the move back
+        if let Some(position) = moves.iter().rposition(|(o, n)| o == new && n == old) {
+            moves.remove(position);
+            self.changes.borrow_mut().remove(old);
+            return Ok(());
+        }
+        moves.push((old.to_path_buf(), new.to_path_buf()));
+        Ok(())
+    }
+
+    fn prepend(&self, path: &Path, text: &str) -> Result<()> {
+        let mut changes = self.changes.borrow_mut();
+        if !changes.contains_key(path) {
+            // The file is still at its old place
+            let moves = self.moves.borrow();
+            let old = moves
+                .iter()
+                .find(|(_, new)| new == path)
+                .map_or(path, |(old, _)| old);
+            let contents = read_to_string(self.root.join(old))
+                .with_context(|| format!("Couldn't read {:?}", old))?;
+            changes.insert(path.to_path_buf(), (contents.clone(), contents));
+        }
+        let (_, new_contents) = changes.get_mut(path).unwrap();
+        *new_contents = text.to_string() + new_contents;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modification() {
+        let mut patch = Patch::default();
+        patch.modify(Path::new("./a.nix"), "x\ny\n", "x\nz\n");
+        assert_eq!(
+            patch.to_string(),
+            "diff --git a/a.nix b/a.nix\n--- a/a.nix\n+++ b/a.nix\n@@ -1,2 +1,2 @@\n x\n-y\n+z\n"
+        );
+        let mut unchanged = Patch::default();
+        unchanged.modify(Path::new("./a.nix"), "x\n", "x\n");
+        assert!(unchanged.is_empty());
+    }
+
+    #[test]
+    fn rename() {
+        let mut patch = Patch::default();
+        patch.rename(Path::new("./a/x.nix"), Path::new("./b/x.nix"));
+        assert_eq!(
+            patch.to_string(),
+            "diff --git a/a/x.nix b/b/x.nix\nsimilarity index 100%\nrename from a/x.nix\nrename to b/x.nix\n"
+        );
+    }
+
+    #[test]
+    fn undone_moves_are_dropped() {
+        let recorder = Recorder::new(Path::new("."));
+        let (a, b, c) = (Path::new("./a"), Path::new("./b"), Path::new("./c"));
+        recorder.move_file(a, b).unwrap();
+        recorder.move_file(c, a).unwrap();
+        recorder.move_file(b, a).unwrap();
+        let mut patch = Patch::default();
+        recorder.add_to(&mut patch);
+        assert_eq!(
+            patch.to_string(),
+            "diff --git a/c b/a\nsimilarity index 100%\nrename from c\nrename to a\n"
+        );
+    }
+}
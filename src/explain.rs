@@ -0,0 +1,201 @@
+// Explaining in detail how the migration of a single attribute was decided
+use crate::all_packages::AllPackages;
+use crate::config::{unprefixed, Config};
+use crate::index::GlobalIndex;
+use crate::migrate;
+use crate::ownership::{referencers, Ownership};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+// The files transitively referenced by a package file within its directory
+struct Closure {
+    // Each file with the file and index of the reference it was first reached through, `None` for
+    // the package file itself
+    parents: BTreeMap<PathBuf, Option<(PathBuf, usize)>>,
+    // References from the closure to paths outside the directory, which aren't followed
+    escaping: Vec<(PathBuf, usize)>,
+}
+
+impl Closure {
+    fn new(index: &GlobalIndex, package_file: &Path) -> Closure {
+        let old_dir = package_file.parent().unwrap();
+        let mut parents = BTreeMap::new();
+        let mut escaping = vec![];
+        parents.insert(package_file.to_path_buf(), None);
+        // Breadth-first, such that the chains are as short as possible
+        let mut queue = VecDeque::from([package_file.to_path_buf()]);
+        while let Some(next) = queue.pop_front() {
+            let Some(path_index) = index.path_indices.get(&next) else {
+                continue;
+            };
+            for (reference_index, reference) in path_index.references.iter().enumerate() {
+                if !reference.movable_ancestor.starts_with(old_dir) {
+                    escaping.push((next.clone(), reference_index));
+                    continue;
+                }
+                if !parents.contains_key(&reference.rel_to_root) {
+                    parents.insert(reference.rel_to_root.clone(), Some((next.clone(), reference_index)));
+                    queue.push_back(reference.rel_to_root.clone());
+                }
+            }
+        }
+        Closure { parents, escaping }
+    }
+
+    // The references leading from the package file to the file, in order
+    fn chain(&self, file: &Path) -> Vec<(PathBuf, usize)> {
+        let mut chain = vec![];
+        let mut current = file.to_path_buf();
+        while let Some(Some((parent, reference_index))) = self.parents.get(&current) {
+            chain.push((parent.clone(), *reference_index));
+            current = parent.clone();
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+// Describes a reference as `<file>:<line>:<column> "<text>"`
+fn describe(index: &GlobalIndex, file: &Path, reference_index: usize) -> String {
+    let reference = &index.path_indices.get(file).unwrap().references[reference_index];
+    format!(
+        "{}:{}:{} {:?}",
+        unprefixed(file).display(),
+        reference.line,
+        reference.column,
+        reference.text
+    )
+}
+
+// A human readable explanation of the closure, the references into it, the sibling files and the
+// result of the analysis of an attribute
+pub fn explain(
+    index: &GlobalIndex,
+    all_packages: &AllPackages,
+    config: &Config,
+    attr: &str,
+) -> Result<String> {
+    let Some(entry) = all_packages.entries.get(attr) else {
+        match all_packages.blocked.iter().find(|blocked| blocked.attr == attr) {
+            Some(blocked) => bail!(
+                "Attribute {:?} on line {} can't be migrated: {}",
+                attr,
+                blocked.line,
+                blocked.reason
+            ),
+            None => bail!("Attribute {:?} isn't defined in {:?}", attr, all_packages.path),
+        }
+    };
+    let mut out = String::new();
+    let closure = Closure::new(index, &entry.path);
+    let old_dir = entry.path.parent().unwrap();
+    let entry_reference = format!(
+        "{}:{}:{} callPackage",
+        unprefixed(&all_packages.path).display(),
+        entry.line,
+        entry.column
+    );
+
+    writeln!(out, "Attribute {:?} is defined at {} with file {}", attr, entry_reference, unprefixed(&entry.path).display())?;
+
+    writeln!(out, "\nClosure, files transitively referenced within {}:", unprefixed(old_dir).display())?;
+    for (file, parent) in &closure.parents {
+        match parent {
+            Some((parent, reference_index)) => {
+                writeln!(out, "  {}, reached through {}", unprefixed(file).display(), describe(index, parent, *reference_index))?
+            }
+            None => writeln!(out, "  {}, the package file", unprefixed(file).display())?,
+        }
+        for (source, reference_index) in referencers(index, file) {
+            let inside = closure.parents.contains_key(source);
+            let status = if inside { "inside" } else if source == &all_packages.path { "all-packages.nix" } else { "outside" };
+            writeln!(out, "    referenced by {} ({})", describe(index, source, *reference_index), status)?;
+        }
+    }
+
+    writeln!(out, "\nOther files in {}:", unprefixed(old_dir).display())?;
+    let ownership = Ownership::new(index, all_packages, old_dir);
+    let siblings: Vec<_> = ownership
+        .files
+        .iter()
+        .filter(|(path, _)| !closure.parents.contains_key(*path))
+        .collect();
+    if siblings.is_empty() {
+        writeln!(out, "  none")?;
+    }
+    for (sibling, owner) in siblings {
+        if ownership.moves_with(sibling, attr) {
+            writeln!(out, "  {}: moved along, since it {}", unprefixed(sibling).display(), owner)?
+        } else {
+            writeln!(out, "  {}: stays behind, since it {}", unprefixed(sibling).display(), owner)?
+        }
+    }
+
+    // Every blocker, not just the first one the analysis stops at
+    let mut blockers = vec![];
+    for (file, reference_index) in &closure.escaping {
+        let mut chain = closure.chain(file);
+        chain.push((file.clone(), *reference_index));
+        blockers.push((
+            format!("{} leaves {}", describe(index, file, *reference_index), unprefixed(old_dir).display()),
+            chain,
+        ));
+    }
+    for file in closure.parents.keys() {
+        let Some(path_index) = index.path_indices.get(file) else {
+            continue;
+        };
+        for (source, reference_index) in &path_index.referenced_by {
+            let reference = &index.path_indices.get(source).unwrap().references[*reference_index];
+            if migrate::from_definition(all_packages, attr, source, reference.offset, file)
+                || closure.parents.contains_key(source)
+            {
+                continue;
+            }
+            let mut chain = closure.chain(file);
+            chain.push((source.clone(), *reference_index));
+            blockers.push((format!("{} refers into the closure", describe(index, source, *reference_index)), chain));
+        }
+    }
+
+    match migrate::analyse(index, all_packages, config, attr) {
+        Ok(migration) => writeln!(out, "\nResult: can be migrated to {}", unprefixed(&migration.unit_dir).display())?,
+        Err(diagnostic) => writeln!(out, "\nResult: blocked, {}", diagnostic.message)?,
+    }
+    for (blocker, chain) in blockers {
+        writeln!(out, "\nBlocker: {}", blocker)?;
+        writeln!(out, "  {} -> {}", entry_reference, unprefixed(&entry.path).display())?;
+        for (file, reference_index) in chain {
+            let target = &index.path_indices.get(&file).unwrap().references[reference_index].rel_to_root;
+            writeln!(out, "  {} -> {}", describe(index, &file, reference_index), unprefixed(target).display())?;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    #[test]
+    fn closure_referencers() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { patches = [ ./fix.patch ]; }\n"),
+            ("pkgs/tools/foo/fix.patch", ""),
+        ]);
+        let index = project.index();
+        let config = Config::default();
+        let all_packages = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        let out = explain(&index, &all_packages, &config, "foo").unwrap();
+        assert!(out.contains(
+            "Closure, files transitively referenced within pkgs/tools/foo:\n  pkgs/tools/foo/default.nix, the package file\n    referenced by pkgs/top-level/all-packages.nix:3:21 \"../tools/foo\" (all-packages.nix)\n  pkgs/tools/foo/fix.patch, reached through pkgs/tools/foo/default.nix:1:20 \"./fix.patch\"\n    referenced by pkgs/tools/foo/default.nix:1:20 \"./fix.patch\" (inside)\n\n"
+        ));
+    }
+}
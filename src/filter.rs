@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use std::fs::read_to_string;
+use std::path::Path;
+
+// A pattern matching attribute names. Either a glob like `python3*`, or a regular expression
+// when prefixed with `re:`, like `re:^python3[0-9]+$`
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> Result<Pattern> {
+        if let Some(regex) = pattern.strip_prefix("re:") {
+            let regex = Regex::new(regex)
+                .with_context(|| format!("Invalid attribute regex {:?}", regex))?;
+            Ok(Pattern::Regex(regex))
+        } else {
+            let glob = Glob::new(pattern)
+                .with_context(|| format!("Invalid attribute glob {:?}", pattern))?;
+            Ok(Pattern::Glob(glob.compile_matcher()))
+        }
+    }
+
+    pub fn matches(&self, attr: &str) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob.is_match(attr),
+            Pattern::Regex(regex) => regex.is_match(attr),
+        }
+    }
+}
+
+// Reads patterns from a file, one per line. Empty lines and lines starting with `#` are ignored
+pub fn load_patterns(path: &Path) -> Result<Vec<String>> {
+    let contents = read_to_string(path)
+        .with_context(|| format!("Couldn't read attribute file {:?}", path))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+// Selects which all-packages.nix attributes to process
+#[derive(Debug, Clone, Default)]
+pub struct AttrFilter {
+    // If empty, all attributes are included
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl AttrFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<AttrFilter> {
+        Ok(AttrFilter {
+            include: include
+                .iter()
+                .map(|p| Pattern::parse(p))
+                .collect::<Result<_>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| Pattern::parse(p))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn matches(&self, attr: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(attr)))
+            && !self.exclude.iter().any(|p| p.matches(attr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> AttrFilter {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        AttrFilter::new(&strings(include), &strings(exclude)).unwrap()
+    }
+
+    #[test]
+    fn everything_by_default() {
+        assert!(filter(&[], &[]).matches("hello"));
+    }
+
+    #[test]
+    fn globs() {
+        let filter = filter(&["python3*"], &["*Packages"]);
+        assert!(filter.matches("python310"));
+        assert!(!filter.matches("python310Packages"));
+        assert!(!filter.matches("perl"));
+    }
+
+    #[test]
+    fn regexes() {
+        let filter = filter(&["re:^python3[0-9]+$"], &[]);
+        assert!(filter.matches("python310"));
+        assert!(!filter.matches("python3"));
+        assert!(!filter.matches("python310Full"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(Pattern::parse("re:(").is_err());
+        assert!(Pattern::parse("[").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+// Integration with a local git repository through the `git` command
+use crate::migrate::{FileSystem, Mover};
+use anyhow::{bail, Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const BLAME_IGNORE_REVS: &str = ".git-blame-ignore-revs";
+
+pub struct Git {
+    // The project root, which needs to be inside a git work tree
+    root: PathBuf,
+}
+
+impl Git {
+    pub fn open(root: &Path) -> Result<Git> {
+        let git = Git {
+            root: root.to_path_buf(),
+        };
+        let inside = git
+            .run(&["rev-parse", "--is-inside-work-tree"])
+            .with_context(|| format!("{:?} is not inside a git repository", root))?;
+        if inside != "true" {
+            bail!("{:?} is not inside a git work tree", root);
+        }
+        Ok(git)
+    }
+
+    // Runs git in the project root, returning its trimmed stdout
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .current_dir(&self.root)
+            .args(args)
+            .output()
+            .context("Couldn't run git")?;
+        if !output.status.success() {
+            bail!(
+                "`git {}` failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    fn run_paths(&self, args: &[&str], paths: &[&Path]) -> Result<String> {
+        let paths: Vec<_> = paths.iter().map(|p| p.to_string_lossy()).collect();
+        let mut all: Vec<&str> = args.to_vec();
+        all.push("--");
+        all.extend(paths.iter().map(|p| p.as_ref()));
+        self.run(&all)
+    }
+
+    pub fn has_staged_changes(&self) -> Result<bool> {
+        // Exits with 1 if there are differences, and with other codes on errors
+        let output = Command::new("git")
+            .current_dir(&self.root)
+            .args(["diff", "--cached", "--quiet"])
+            .output()
+            .context("Couldn't run git")?;
+        match output.status.code() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => bail!(
+                "`git diff` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        }
+    }
+
+    pub fn add(&self, path: &Path) -> Result<()> {
+        self.run_paths(&["add"], &[path]).map(|_| ())
+    }
+
+    // Commits the staged changes, returning the id of the new commit
+    pub fn commit(&self, message: &str) -> Result<String> {
+        self.run(&["commit", "--quiet", "--message", message])?;
+        self.run(&["rev-parse", "HEAD"])
+    }
+
+    // Appends commits to the file listing commits for `git blame` to ignore and stages it
+    pub fn add_blame_ignore_revs(&self, commits: &[(String, String)]) -> Result<()> {
+        let path = self.root.join(BLAME_IGNORE_REVS);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Couldn't open {:?}", path))?;
+        for (id, subject) in commits {
+            writeln!(file, "\n# {}\n{}", subject, id)
+                .with_context(|| format!("Couldn't write {:?}", path))?;
+        }
+        self.add(Path::new(BLAME_IGNORE_REVS))
+    }
+}
+
+// Moves files with `git mv`, such that git's rename detection picks them up
+impl Mover for Git {
+    fn move_file(&self, old: &Path, new: &Path) -> Result<()> {
+        // Untracked files can't be moved with git
+        if self.run_paths(&["ls-files", "--error-unmatch"], &[old]).is_err() {
+            return FileSystem {
+                root: self.root.clone(),
+            }
+            .move_file(old, new);
+        }
+        let parent = self.root.join(new.parent().unwrap());
+        std::fs::create_dir_all(&parent)
+            .with_context(|| format!("Couldn't create directory {:?}", parent))?;
+        self.run_paths(&["mv"], &[old, new]).map(|_| ())
+    }
+
+    fn prepend(&self, path: &Path, text: &str) -> Result<()> {
+        FileSystem {
+            root: self.root.clone(),
+        }
+        .prepend(path, text)?;
+        self.add(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    // A repository with the files committed, except for the untracked ones
+    fn repository(files: &[(&str, &str)], untracked: &[(&str, &str)]) -> (TempProject, Git) {
+        let project = TempProject::new(files);
+        let git = Git {
+            root: project.root.clone(),
+        };
+        git.run(&["init", "--quiet"]).unwrap();
+        git.run(&["config", "user.name", "Test"]).unwrap();
+        git.run(&["config", "user.email", "test@example.org"]).unwrap();
+        git.run(&["config", "commit.gpgsign", "false"]).unwrap();
+        git.run(&["add", "--all"]).unwrap();
+        git.commit("Initial").unwrap();
+        for (path, contents) in untracked {
+            project.write(path, contents);
+        }
+        let git = Git::open(&project.root).unwrap();
+        (project, git)
+    }
+
+    #[test]
+    fn moves_are_staged() {
+        let (project, git) = repository(
+            &[("pkgs/tools/foo/default.nix", "{ }: { }\n")],
+            &[("pkgs/tools/foo/notes.txt", "")],
+        );
+        assert!(!git.has_staged_changes().unwrap());
+        git.move_file(Path::new("./pkgs/tools/foo/default.nix"), Path::new("./pkgs/unit/fo/foo/pkg-fun.nix"))
+            .unwrap();
+        git.move_file(Path::new("./pkgs/tools/foo/notes.txt"), Path::new("./pkgs/unit/fo/foo/notes.txt"))
+            .unwrap();
+        git.prepend(Path::new("./pkgs/unit/fo/foo/pkg-fun.nix"), "# Foo\n").unwrap();
+        assert!(git.has_staged_changes().unwrap());
+        assert_eq!(
+            git.run(&["status", "--short"]).unwrap(),
+            "R  pkgs/tools/foo/default.nix -> pkgs/unit/fo/foo/pkg-fun.nix\n?? pkgs/unit/fo/foo/notes.txt"
+        );
+        assert_eq!(project.read("pkgs/unit/fo/foo/pkg-fun.nix"), "# Foo\n{ }: { }\n");
+    }
+
+    #[test]
+    fn blame_ignore_revs() {
+        let (project, git) = repository(&[("default.nix", "")], &[]);
+        project.write("default.nix", "{ }\n");
+        git.add(Path::new("default.nix")).unwrap();
+        let id = git.commit("foo: migrate to pkgs/unit/fo/foo").unwrap();
+        assert_eq!(id, git.run(&["rev-parse", "HEAD"]).unwrap());
+
+        git.add_blame_ignore_revs(&[(id.clone(), "foo: migrate to pkgs/unit/fo/foo".to_string())])
+            .unwrap();
+        assert_eq!(
+            project.read(BLAME_IGNORE_REVS),
+            format!("\n# foo: migrate to pkgs/unit/fo/foo\n{}\n", id)
+        );
+        assert_eq!(git.run(&["diff", "--cached", "--name-only"]).unwrap(), BLAME_IGNORE_REVS);
+    }
+
+    #[test]
+    fn not_a_repository() {
+        let project = TempProject::new(&[("default.nix", "")]);
+        // The temporary directory may be inside a repository itself
+        if Command::new("git").current_dir(std::env::temp_dir()).arg("rev-parse").output().unwrap().status.success() {
+            return;
+        }
+        assert!(Git::open(&project.root).is_err());
+    }
+}
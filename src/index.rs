@@ -1,5 +1,8 @@
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
 use crate::line_index::LineIndex;
-use ignore::{DirEntry, Walk};
+use anyhow::{bail, Context, Result};
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
 use rnix::{Root, SyntaxKind::NODE_PATH};
 use rowan::ast::AstNode;
 use std::collections::HashMap;
@@ -12,6 +15,9 @@ use std::path::PathBuf;
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Reference {
     pub line: usize,
+    pub column: usize,
+    // The byte offset of the reference in its file
+    pub offset: usize,
 
     // The most longest ancestor of the referenced path that can be moved
     // around without breaking the reference
@@ -29,76 +35,160 @@ pub struct Reference {
 
 #[derive(Debug, Clone)]
 pub struct PathIndex {
+    pub is_dir: bool,
     pub references: Vec<Reference>,
     pub referenced_by: Vec<(PathBuf, usize)>,
 }
 
 impl PathIndex {
-    fn new() -> PathIndex {
+    fn new(is_dir: bool) -> PathIndex {
         PathIndex {
+            is_dir,
             references: Vec::new(),
             referenced_by: Vec::new(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct GlobalIndex {
-    // For each Nix file, what paths it references
-    pub path_indices: HashMap<PathBuf, PathIndex>,
+// Why a path reference couldn't be resolved to a path in the project
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum InvalidReferenceKind {
+    NonExistent,
+    // The path exists, but is ignored by e.g. .gitignore, so it isn't indexed
+    Ignored,
+    Absolute,
+    // Such as `./foo/../bar`
+    InterleavedParent,
+    EscapesRoot,
 }
 
-enum Tree {
-    Dir(HashMap<String, Tree>),
-    File(Vec<Reference>),
-}
-//////
+impl InvalidReferenceKind {
+    // Whether this is a bug in the project, as opposed to a reference we just can't analyse
+    pub fn is_error(&self) -> bool {
+        !matches!(self, InvalidReferenceKind::Ignored)
+    }
 
-enum Edge {
-    Reference,
-    DirEntry(String),
+    pub fn code(&self) -> Code {
+        match self {
+            InvalidReferenceKind::NonExistent => Code::NonExistentReference,
+            InvalidReferenceKind::Ignored => Code::IgnoredReference,
+            InvalidReferenceKind::Absolute => Code::AbsoluteReference,
+            InvalidReferenceKind::InterleavedParent => Code::InterleavedParentReference,
+            InvalidReferenceKind::EscapesRoot => Code::RootEscapingReference,
+        }
+    }
 }
 
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct InvalidReference {
+    // The file containing the reference, relative to the project root
+    pub source: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    pub kind: InvalidReferenceKind,
+}
 
-// Arena
-// https://crates.io/crates/atree
-// pkgs/development/libraries/readline/update-patch-set.sh -> pkgs/shells/bash/update-packag-set.sh
-
-
-
-// Nodes: Paths
-// Edges: Contains (directory listing)
-//        References
+impl InvalidReference {
+    pub fn diagnostic(&self, severity: Severity) -> Diagnostic {
+        let description = match self.kind {
+            InvalidReferenceKind::NonExistent => "refers to a non-existent path",
+            InvalidReferenceKind::Ignored => "refers to an ignored path",
+            InvalidReferenceKind::Absolute => "is absolute",
+            InvalidReferenceKind::InterleavedParent => "has an interleaved `..` segment",
+            InvalidReferenceKind::EscapesRoot => "escapes the project root",
+        };
+        Diagnostic::new(
+            severity,
+            self.kind.code(),
+            Location::new(&self.source, self.line, self.column),
+            format!("Path reference {:?} {}", self.text, description),
+        )
+    }
+}
 
-// Move all files from one directory to another
+#[derive(Debug, Clone)]
+pub struct GlobalIndex {
+    // The project root, all other paths are relative to this and start with `./`
+    pub root: PathBuf,
+    // For each Nix file, what paths it references
+    pub path_indices: HashMap<PathBuf, PathIndex>,
+    // The paths directly in each directory, sorted
+    pub dir_entries: HashMap<PathBuf, Vec<PathBuf>>,
+    // All path references that couldn't be resolved, in the order they were found
+    pub invalid_references: Vec<InvalidReference>,
+    // Files and references that were skipped for other reasons
+    pub diagnostics: Vec<Diagnostic>,
+}
 
 impl GlobalIndex {
-    pub fn new(path: impl AsRef<Path>) -> GlobalIndex {
-        std::env::set_current_dir(path).unwrap();
-        let subpaths: Vec<_> = Walk::new(".")
+    // Exclusions are gitignore-style globs relative to the root
+    pub fn new(root: impl AsRef<Path>, exclude: &[String]) -> Result<GlobalIndex> {
+        let project_root = root.as_ref();
+        if !project_root.is_dir() {
+            bail!("Project root {:?} is not a directory", project_root);
+        }
+        let mut overrides = OverrideBuilder::new(project_root);
+        for glob in exclude {
+            overrides
+                .add(&format!("!{}", glob))
+                .with_context(|| format!("Invalid exclusion {:?}", glob))?;
+        }
+        let subpaths: Vec<_> = WalkBuilder::new(project_root)
+            .overrides(overrides.build()?)
+            .build()
             .filter_map(Result::ok)
-            .map(DirEntry::into_path)
+            .map(|entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                let rel_to_root = Path::new(".").join(entry.path().strip_prefix(project_root).unwrap());
+                (rel_to_root, is_dir)
+            })
             .collect();
 
+        let mut dir_entries: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        for (path, _) in &subpaths {
+            if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                dir_entries.entry(parent.to_path_buf()).or_default().push(path.clone());
+            }
+        }
+        for entries in dir_entries.values_mut() {
+            entries.sort();
+        }
+
+        let mut invalid_references = vec![];
+        let mut diagnostics = vec![];
         let mut path_indices = subpaths
             .iter()
-            .map(|p| (p.clone(), PathIndex::new()))
+            .map(|(p, is_dir)| (p.clone(), PathIndex::new(*is_dir)))
             .collect();
 
         subpaths
             .iter()
-            .filter(|p| !p.is_dir() && p.extension() == Some(OsStr::new("nix")))
-            .for_each(|subpath| {
+            .filter(|(p, is_dir)| !is_dir && p.extension() == Some(OsStr::new("nix")))
+            .for_each(|(subpath, _)| {
 
-            let contents = read_to_string(&subpath).unwrap();
+            let contents = match read_to_string(project_root.join(subpath)) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        Code::UnreadableFile,
+                        Location::file(subpath),
+                        format!("Couldn't read file, ignoring it: {}", err),
+                    ));
+                    return;
+                }
+            };
 
             let root = match Root::parse(&contents).ok() {
                 Ok(root) => root,
                 Err(err) => {
-                    eprintln!(
-                        "Warning: Couldn't parse file {:?}, ignoring it: {}",
-                        subpath, err
-                    );
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        Code::UnparseableFile,
+                        Location::file(subpath),
+                        format!("Couldn't parse file, ignoring it: {}", err),
+                    ));
                     return;
                 }
             };
@@ -110,34 +200,56 @@ impl GlobalIndex {
                     continue 'nodes;
                 }
                 let text = node.text().to_string();
-                let line = line_index.line(node.text_range().start().into());
+                let start = node.text_range().start().into();
+                let line = line_index.line(start);
+                let column = line_index.column(start);
 
                 // Filters out ./foo/${bar}/baz
                 if node.children().count() != 0 {
-                    eprintln!("Note: File {:?} on line {:?} contains a path with a subexpressions, ignoring it: {}", subpath, line, text);
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Note,
+                        Code::InterpolatedReference,
+                        Location::new(subpath, line, column),
+                        format!("Path {} contains subexpressions, ignoring it", text),
+                    ));
                     continue 'nodes;
                 }
                 // Filters out search paths like <nixpkgs>
                 if str::starts_with(&text, "<") {
-                    eprintln!("Warning: File {:?} on line {:?} refers to Nix search path, ignoring it: {:?}", subpath, line, text);
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        Code::SearchPathReference,
+                        Location::new(subpath, line, column),
+                        format!("Path {:?} refers to the Nix search path, ignoring it", text),
+                    ));
                     continue 'nodes;
                 }
 
-                let (rel_to_source, movable_ancestor, rel_to_root) = if let Some(resolved) =
-                    resolve_reference(&subpath, line, &PathBuf::from(&text), &path_indices)
-                {
-                    resolved
-                } else {
-                    continue 'nodes;
-                };
+                let (rel_to_source, movable_ancestor, rel_to_root) =
+                    match resolve_reference(project_root, subpath, &PathBuf::from(&text), &path_indices) {
+                        Ok(resolved) => resolved,
+                        Err(kind) => {
+                            let invalid = InvalidReference {
+                                source: subpath.clone(),
+                                line,
+                                column,
+                                text,
+                                kind,
+                            };
+                            invalid_references.push(invalid);
+                            continue 'nodes;
+                        }
+                    };
 
                 let reference = Reference {
                     line,
+                    column,
+                    offset: start,
                     movable_ancestor,
                     rel_to_root,
                     text,
                 };
-                let path_index = path_indices.get_mut(&*subpath).unwrap();
+                let path_index = path_indices.get_mut(subpath).unwrap();
                 let current_length = path_index.references.len();
                 let pointer = (subpath.clone(), current_length);
 
@@ -179,20 +291,42 @@ impl GlobalIndex {
             }
         });
 
-        GlobalIndex { path_indices }
+        Ok(GlobalIndex {
+            root: project_root.to_path_buf(),
+            path_indices,
+            dir_entries,
+            invalid_references,
+            diagnostics,
+        })
+    }
+
+    // The indexed path and everything below it, in order, without going through the whole index
+    pub fn paths_under(&self, dir: &Path) -> Vec<&PathBuf> {
+        let mut paths = vec![];
+        let Some((dir, _)) = self.path_indices.get_key_value(dir) else {
+            return paths;
+        };
+        let mut stack = vec![dir];
+        while let Some(path) = stack.pop() {
+            paths.push(path);
+            if let Some(entries) = self.dir_entries.get(path) {
+                stack.extend(entries.iter().rev());
+            }
+        }
+        paths
     }
 }
 
-// Absolute project root path
+// Root is the project root path
 // Source path is where the reference is, relative to project root
 // reference is the reference string, any format
 pub fn resolve_reference(
-    source: &PathBuf,
-    line: usize,
-    reference: &PathBuf,
+    root: &Path,
+    source: &Path,
+    reference: &Path,
     known_files: &HashMap<PathBuf, PathIndex>,
-) -> Option<(PathBuf, PathBuf, PathBuf)> {
-    let mut rel_to_source = reference.clone();
+) -> Result<(PathBuf, PathBuf, PathBuf), InvalidReferenceKind> {
+    let mut rel_to_source = reference.to_path_buf();
     let mut movable_ancestor = source.parent().unwrap().to_path_buf();
     let mut rel_to_root = movable_ancestor.clone();
     let mut ascending = true;
@@ -201,21 +335,11 @@ pub fn resolve_reference(
             Component::CurDir => {}
             Component::ParentDir => {
                 if !ascending {
-                    eprintln!("Warning: File {:?} on line {:?} contains a path with an interleaved `..` segment, ignoring it: {:?}", source, line, reference);
-                    return None;
+                    return Err(InvalidReferenceKind::InterleavedParent);
                 }
                 movable_ancestor = match movable_ancestor.parent() {
-                    None => {
-                        eprintln!("Parent doesn't exist");
-                        return None;
-                    }
-                    Some(parent) => {
-                        if !parent.starts_with(".") {
-                            eprintln!("Warning: File {:?} on line {:?} refers to a path that escapes the project root, ignoring it: {:?}", source, line, reference);
-                            return None;
-                        }
-                        parent.to_path_buf()
-                    }
+                    Some(parent) if parent.starts_with(".") => parent.to_path_buf(),
+                    _ => return Err(InvalidReferenceKind::EscapesRoot),
                 };
                 rel_to_root = movable_ancestor.clone();
             }
@@ -223,28 +347,122 @@ pub fn resolve_reference(
                 ascending = false;
                 rel_to_root = rel_to_root.join(segment);
                 if !known_files.contains_key(&rel_to_root) {
-                    if rel_to_root.exists() {
-                        eprintln!("Warning: File {:?} on line {:?} refers to an ignored path, ignoring it: {:?}", source, line, reference);
+                    if root.join(&rel_to_root).exists() {
+                        return Err(InvalidReferenceKind::Ignored);
                     } else {
-                        eprintln!("Warning: File {:?} on line {:?} refers to non-existent path, ignoring it {:?}", source, line, reference);
+                        return Err(InvalidReferenceKind::NonExistent);
                     }
-                    return None;
                 }
             }
             Component::RootDir | Component::Prefix(_) => {
-                eprintln!(
-                    "Warning: File {:?} on line {:?} refers to absolute path, ignoring it: {:?}",
-                    source, line, reference
-                );
-                return None;
+                return Err(InvalidReferenceKind::Absolute);
             }
         }
     }
 
     // This should only be done for the top-level
-    if rel_to_root.is_dir() && known_files.contains_key(&rel_to_root.join("default.nix")) {
+    if known_files.get(&rel_to_root).is_some_and(|index| index.is_dir)
+        && known_files.contains_key(&rel_to_root.join("default.nix"))
+    {
         rel_to_root = rel_to_root.join("default.nix");
         rel_to_source = rel_to_source.join("default.nix");
     }
-    Some((rel_to_source, movable_ancestor, rel_to_root))
+    Ok((rel_to_source, movable_ancestor, rel_to_root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    #[test]
+    fn invalid_references() {
+        let project = TempProject::new(&[
+            (".ignore", "ignored.nix\n"),
+            ("ignored.nix", ""),
+            ("dir/foo/default.nix", ""),
+            (
+                "dir/refs.nix",
+                "[ ./missing ../ignored.nix /etc/passwd ./foo/../foo ../../outside ./foo/missing ]\n",
+            ),
+        ]);
+        let index = project.index();
+        let invalid: Vec<_> = index
+            .invalid_references
+            .iter()
+            .map(|invalid| (invalid.text.as_str(), invalid.column, invalid.kind))
+            .collect();
+        assert_eq!(
+            invalid,
+            [
+                ("./missing", 3, InvalidReferenceKind::NonExistent),
+                ("../ignored.nix", 13, InvalidReferenceKind::Ignored),
+                ("/etc/passwd", 28, InvalidReferenceKind::Absolute),
+                ("./foo/../foo", 40, InvalidReferenceKind::InterleavedParent),
+                ("../../outside", 53, InvalidReferenceKind::EscapesRoot),
+                ("./foo/missing", 67, InvalidReferenceKind::NonExistent),
+            ]
+        );
+        assert!(index.invalid_references.iter().all(|invalid| invalid.source == Path::new("./dir/refs.nix")));
+        assert!(!InvalidReferenceKind::Ignored.is_error());
+        assert!(InvalidReferenceKind::EscapesRoot.is_error());
+    }
+
+    #[test]
+    fn resolves_references() {
+        let project = TempProject::new(&[
+            ("pkgs/tools/foo/default.nix", ""),
+            ("pkgs/tools/foo/fix.patch", ""),
+            ("pkgs/top-level/all-packages.nix", ""),
+        ]);
+        let index = project.index();
+        let resolve = |source: &str, reference: &str| {
+            resolve_reference(&index.root, Path::new(source), Path::new(reference), &index.path_indices)
+        };
+        // Directories resolve to their default.nix
+        assert_eq!(
+            resolve("./pkgs/top-level/all-packages.nix", "../tools/foo"),
+            Ok((
+                PathBuf::from("../tools/foo/default.nix"),
+                PathBuf::from("./pkgs"),
+                PathBuf::from("./pkgs/tools/foo/default.nix")
+            ))
+        );
+        assert_eq!(
+            resolve("./pkgs/tools/foo/default.nix", "./fix.patch"),
+            Ok((
+                PathBuf::from("./fix.patch"),
+                PathBuf::from("./pkgs/tools/foo"),
+                PathBuf::from("./pkgs/tools/foo/fix.patch")
+            ))
+        );
+        assert_eq!(
+            resolve("./pkgs/tools/foo/default.nix", "../../../../foo"),
+            Err(InvalidReferenceKind::EscapesRoot)
+        );
+    }
+
+    #[test]
+    fn paths_under() {
+        let project = TempProject::new(&[
+            ("pkgs/tools/foo/default.nix", ""),
+            ("pkgs/tools/foo/patches/fix.patch", ""),
+            ("pkgs/tools/foobar/default.nix", ""),
+        ]);
+        let index = project.index();
+        assert_eq!(
+            index.paths_under(Path::new("./pkgs/tools/foo")),
+            [
+                Path::new("./pkgs/tools/foo"),
+                Path::new("./pkgs/tools/foo/default.nix"),
+                Path::new("./pkgs/tools/foo/patches"),
+                Path::new("./pkgs/tools/foo/patches/fix.patch"),
+            ]
+        );
+        assert_eq!(
+            index.paths_under(Path::new("./pkgs/tools/foo/default.nix")),
+            [Path::new("./pkgs/tools/foo/default.nix")]
+        );
+        assert!(index.paths_under(Path::new("./pkgs/missing")).is_empty());
+    }
 }
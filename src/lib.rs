@@ -0,0 +1,23 @@
+pub mod all_packages;
+pub mod baseline;
+pub mod batch;
+pub mod boundary;
+pub mod check_references;
+pub mod config;
+pub mod convert;
+pub mod diagnostic;
+pub mod diff;
+pub mod explain;
+pub mod filter;
+pub mod git;
+pub mod index;
+pub mod line_index;
+pub mod lsp;
+pub mod migrate;
+pub mod naming;
+pub mod ownership;
+pub mod report;
+pub mod sarif;
+#[cfg(test)]
+mod test_util;
+pub mod unmigrate;
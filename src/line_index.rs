@@ -1,13 +1,13 @@
-// A simple utility for calculating the line for a string offset
+// A simple utility for calculating the line and column for a string offset
 pub struct LineIndex {
     newlines: Vec<usize>,
 }
 
 impl LineIndex {
-    pub fn new(s: &String) -> LineIndex {
+    pub fn new(s: &str) -> LineIndex {
         let mut newlines = vec![];
         let mut index = 0;
-        for split in s.split_inclusive("\n") {
+        for split in s.split_inclusive('\n') {
             index += split.len();
             newlines.push(index);
         }
@@ -16,8 +16,18 @@ impl LineIndex {
 
     pub fn line(&self, index: usize) -> usize {
         match self.newlines.binary_search(&index) {
-            Ok(x) => x + 1,
+            // The index is right after a newline, so it's the start of the next line
+            Ok(x) => x + 2,
             Err(x) => x + 1,
         }
     }
+
+    // The 1-based column of the offset, counted in bytes
+    pub fn column(&self, index: usize) -> usize {
+        let line_start = match self.line(index) {
+            1 => 0,
+            line => self.newlines[line - 2],
+        };
+        index - line_start + 1
+    }
 }
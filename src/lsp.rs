@@ -0,0 +1,417 @@
+// A language server showing the diagnostics of the index while editing, and navigating path
+// references. The index is rebuilt whenever Nix files or the configuration are saved or change
+// on disk
+use crate::all_packages::AllPackages;
+use crate::boundary::unit_boundary_violations;
+use crate::config::{rel_to_root, unprefixed, Config, CONFIG_FILE};
+use crate::diagnostic::{self, Code, Severity};
+use crate::index::{resolve_reference, GlobalIndex};
+use crate::migrate;
+use anyhow::{Context, Result};
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::notification::{
+    DidChangeWatchedFiles, DidSaveTextDocument, LogMessage, Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{GotoDefinition, References, RegisterCapability, Request as _};
+use lsp_types::{
+    DiagnosticRelatedInformation, DidChangeWatchedFilesParams,
+    DidChangeWatchedFilesRegistrationOptions, DidSaveTextDocumentParams, FileSystemWatcher,
+    GlobPattern, GotoDefinitionParams, GotoDefinitionResponse, InitializeParams, Location,
+    LogMessageParams, MessageType, NumberOrString, OneOf, Position, PublishDiagnosticsParams, Range,
+    ReferenceParams, Registration, RegistrationParams, SaveOptions, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncOptions, TextDocumentSyncSaveOptions, Url,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// How long to wait for more changes before rebuilding the index, such that e.g. a checkout
+// changing many files only rebuilds it once
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+struct Server<'a> {
+    connection: Connection,
+    // Absolute, such that file URLs can be created
+    root: PathBuf,
+    // Reads the configuration again when it changes
+    load_config: &'a dyn Fn() -> Result<Config>,
+    config: Config,
+    index: GlobalIndex,
+    // Files diagnostics were last published for, which need to be cleared if they're fixed
+    published: HashSet<PathBuf>,
+    // The lines of files positions were converted for since the index was last built
+    lines: RefCell<HashMap<PathBuf, Vec<String>>>,
+}
+
+// Serves the language server protocol on stdin and stdout until the client exits
+pub fn run(root: &Path, load_config: &dyn Fn() -> Result<Config>) -> Result<()> {
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("Couldn't resolve {:?}", root))?;
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions::default())),
+            ..TextDocumentSyncOptions::default()
+        })),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    let params: InitializeParams =
+        serde_json::from_value(connection.initialize(serde_json::to_value(capabilities)?)?)?;
+    // Changes to files that aren't open are only noticed if the client can watch them
+    let can_watch = params
+        .capabilities
+        .workspace
+        .and_then(|workspace| workspace.did_change_watched_files)
+        .and_then(|watched| watched.dynamic_registration)
+        .unwrap_or(false);
+
+    let config = load_config()?;
+    let index = GlobalIndex::new(&root, &config.exclude)?;
+    let mut server = Server {
+        connection,
+        root,
+        load_config,
+        config,
+        index,
+        published: HashSet::new(),
+        lines: RefCell::new(HashMap::new()),
+    };
+    if can_watch {
+        server.watch_files()?;
+    }
+    server.publish_diagnostics()?;
+    server.main_loop()?;
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
+
+impl Server<'_> {
+    fn main_loop(&mut self) -> Result<()> {
+        // Whether relevant files changed since the index was built
+        let mut stale = false;
+        loop {
+            let message = if stale {
+                match self.connection.receiver.recv_timeout(DEBOUNCE) {
+                    Ok(message) => message,
+                    Err(err) if err.is_timeout() => {
+                        stale = false;
+                        self.reindex()?;
+                        continue;
+                    }
+                    Err(_) => break,
+                }
+            } else {
+                match self.connection.receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => break,
+                }
+            };
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    self.handle_request(request)?;
+                }
+                Message::Notification(notification) => {
+                    stale |= self.changes_index(notification);
+                }
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the notification is about a change to a file the index or configuration depends on
+    fn changes_index(&self, notification: Notification) -> bool {
+        let uris = match notification.method.as_str() {
+            DidSaveTextDocument::METHOD => {
+                serde_json::from_value::<DidSaveTextDocumentParams>(notification.params)
+                    .map(|params| vec![params.text_document.uri])
+            }
+            DidChangeWatchedFiles::METHOD => {
+                serde_json::from_value::<DidChangeWatchedFilesParams>(notification.params)
+                    .map(|params| params.changes.into_iter().map(|change| change.uri).collect())
+            }
+            _ => return false,
+        };
+        uris.unwrap_or_default().iter().any(|uri| {
+            self.path(uri).is_some_and(|path| {
+                !path.starts_with("./.git")
+                    && (path.extension().is_some_and(|extension| extension == "nix")
+                        || path == Path::new(".").join(CONFIG_FILE))
+            })
+        })
+    }
+
+    // Rebuilds the index and publishes its diagnostics. If that fails, e.g. because the
+    // configuration is invalid, the error is logged and the previous index is kept
+    fn reindex(&mut self) -> Result<()> {
+        let result = (self.load_config)().and_then(|config| {
+            let index = GlobalIndex::new(&self.root, &config.exclude)?;
+            Ok((config, index))
+        });
+        match result {
+            Ok((config, index)) => {
+                self.config = config;
+                self.index = index;
+                self.lines.borrow_mut().clear();
+                self.publish_diagnostics()
+            }
+            Err(err) => self.send(Message::Notification(Notification::new(
+                LogMessage::METHOD.to_string(),
+                LogMessageParams {
+                    typ: MessageType::ERROR,
+                    message: format!("Couldn't rebuild the index: {:#}", err),
+                },
+            ))),
+        }
+    }
+
+    // Asks the client to notify about changes to Nix files and the configuration, not just the
+    // ones open in the editor
+    fn watch_files(&self) -> Result<()> {
+        let watcher = |glob: String| FileSystemWatcher {
+            glob_pattern: GlobPattern::String(glob),
+            kind: None,
+        };
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![
+                watcher("**/*.nix".to_string()),
+                watcher(format!("**/{}", CONFIG_FILE)),
+            ],
+        };
+        let params = RegistrationParams {
+            registrations: vec![Registration {
+                id: "watch-files".to_string(),
+                method: DidChangeWatchedFiles::METHOD.to_string(),
+                register_options: Some(serde_json::to_value(options)?),
+            }],
+        };
+        self.send(Message::Request(Request::new(
+            RequestId::from("watch-files".to_string()),
+            RegisterCapability::METHOD.to_string(),
+            params,
+        )))
+    }
+
+    fn handle_request(&self, request: Request) -> Result<()> {
+        let response = match request.method.as_str() {
+            GotoDefinition::METHOD => {
+                let (id, params) = request.extract::<GotoDefinitionParams>(GotoDefinition::METHOD)?;
+                let position = params.text_document_position_params;
+                let result = self
+                    .definition(&position.text_document.uri, position.position)
+                    .map(GotoDefinitionResponse::Scalar);
+                Response::new_ok(id, result)
+            }
+            References::METHOD => {
+                let (id, params) = request.extract::<ReferenceParams>(References::METHOD)?;
+                let result = self.references(&params.text_document_position.text_document.uri);
+                Response::new_ok(id, result)
+            }
+            _ => Response::new_err(
+                request.id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request {:?}", request.method),
+            ),
+        };
+        self.send(Message::Response(response))
+    }
+
+    // The file a path literal at the position refers to
+    fn definition(&self, uri: &Url, position: Position) -> Option<Location> {
+        let source = self.path(uri)?;
+        let path_index = self.index.path_indices.get(&source)?;
+        let reference = path_index.references.iter().find(|reference| {
+            let range = self.range(&source, reference.line, reference.column, &reference.text);
+            range.start.line == position.line
+                && range.start.character <= position.character
+                && position.character <= range.end.character
+        })?;
+        let (_, _, target) = resolve_reference(
+            &self.index.root,
+            &source,
+            Path::new(&reference.text),
+            &self.index.path_indices,
+        )
+        .ok()?;
+        Some(Location::new(self.url(&target)?, Range::default()))
+    }
+
+    // All path literals referring to the file
+    fn references(&self, uri: &Url) -> Vec<Location> {
+        let Some(path_index) = self.path(uri).and_then(|path| self.index.path_indices.get(&path)) else {
+            return vec![];
+        };
+        path_index
+            .referenced_by
+            .iter()
+            .filter_map(|(source, reference_index)| {
+                let reference = &self.index.path_indices.get(source)?.references[*reference_index];
+                Some(Location::new(
+                    self.url(source)?,
+                    self.range(source, reference.line, reference.column, &reference.text),
+                ))
+            })
+            .collect()
+    }
+
+    fn publish_diagnostics(&mut self) -> Result<()> {
+        let mut by_file: BTreeMap<PathBuf, Vec<lsp_types::Diagnostic>> = BTreeMap::new();
+        for diagnostic in self.diagnostics() {
+            by_file
+                .entry(diagnostic.location.file.clone())
+                .or_default()
+                .push(self.lsp_diagnostic(&diagnostic));
+        }
+        // Files that don't have any diagnostics anymore need to be cleared
+        for file in self.published.drain() {
+            by_file.entry(file).or_default();
+        }
+        for (file, diagnostics) in by_file {
+            let Some(uri) = self.url(&file) else {
+                continue;
+            };
+            if !diagnostics.is_empty() {
+                self.published.insert(file);
+            }
+            let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+            self.send(Message::Notification(Notification::new(
+                PublishDiagnostics::METHOD.to_string(),
+                params,
+            )))?;
+        }
+        Ok(())
+    }
+
+    fn diagnostics(&self) -> Vec<diagnostic::Diagnostic> {
+        let mut diagnostics = self.index.diagnostics.clone();
+        diagnostics.extend(
+            self.index
+                .invalid_references
+                .iter()
+                .map(|invalid| invalid.diagnostic(Severity::Warning)),
+        );
+        diagnostics.extend(
+            unit_boundary_violations(&self.index, &self.config)
+                .iter()
+                .map(|violation| violation.diagnostic(Severity::Error)),
+        );
+        // A broken all-packages.nix is already reported by the index
+        if let Ok(all_packages) = AllPackages::new(&self.config.all_packages_path(), &self.index) {
+            diagnostics.extend(all_packages.duplicate_diagnostics(Severity::Error));
+            diagnostics.extend(migrate::shadowed_definitions(
+                &self.index,
+                &all_packages,
+                &self.config,
+                Severity::Warning,
+            ));
+            for (attr, _) in all_packages.entries_in_order() {
+                if let Ok(migration) = migrate::analyse(&self.index, &all_packages, &self.config, attr) {
+                    diagnostics.push(diagnostic::Diagnostic::new(
+                        Severity::Note,
+                        Code::Migratable,
+                        migration.location,
+                        format!("Attribute {:?} can be migrated to {:?}", attr, migration.unit_dir),
+                    ));
+                }
+            }
+        }
+        diagnostics
+    }
+
+    fn lsp_diagnostic(&self, diagnostic: &diagnostic::Diagnostic) -> lsp_types::Diagnostic {
+        let related = diagnostic
+            .related
+            .iter()
+            .filter_map(|related| {
+                Some(DiagnosticRelatedInformation {
+                    location: Location::new(
+                        self.url(&related.location.file)?,
+                        self.location_range(&related.location),
+                    ),
+                    message: related.message.clone(),
+                })
+            })
+            .collect();
+        lsp_types::Diagnostic {
+            range: self.location_range(&diagnostic.location),
+            severity: Some(match diagnostic.severity {
+                Severity::Note => lsp_types::DiagnosticSeverity::INFORMATION,
+                Severity::Warning => lsp_types::DiagnosticSeverity::WARNING,
+                Severity::Error => lsp_types::DiagnosticSeverity::ERROR,
+            }),
+            code: Some(NumberOrString::String(diagnostic.code.id().to_string())),
+            source: Some("nix-spp".to_string()),
+            message: diagnostic.message.clone(),
+            related_information: Some(related),
+            ..lsp_types::Diagnostic::default()
+        }
+    }
+
+    // The path relative to the root in the `./`-prefixed form the index uses
+    fn path(&self, uri: &Url) -> Option<PathBuf> {
+        let path = uri.to_file_path().ok()?;
+        Some(rel_to_root(path.strip_prefix(&self.root).ok()?))
+    }
+
+    // Converts a 1-based line and column counted in bytes into the range of the text there, with
+    // the character offsets in UTF-16 code units editors use
+    fn range(&self, file: &Path, line: usize, column: usize, text: &str) -> Range {
+        let mut lines = self.lines.borrow_mut();
+        let lines = lines.entry(file.to_path_buf()).or_insert_with(|| {
+            read_to_string(self.root.join(file))
+                .map(|contents| contents.lines().map(str::to_string).collect())
+                .unwrap_or_default()
+        });
+        let character = lines.get(line - 1).map_or(column - 1, |text| utf16_offset(text, column));
+        let start = Position::new(line as u32 - 1, character as u32);
+        let end = Position::new(start.line, start.character + text.encode_utf16().count() as u32);
+        Range::new(start, end)
+    }
+
+    fn location_range(&self, location: &diagnostic::Location) -> Range {
+        match (location.line, location.column) {
+            (Some(line), column) => self.range(&location.file, line, column.unwrap_or(1), ""),
+            _ => Range::default(),
+        }
+    }
+
+    fn url(&self, path: &Path) -> Option<Url> {
+        Url::from_file_path(self.root.join(unprefixed(path))).ok()
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.connection
+            .sender
+            .send(message)
+            .context("Couldn't send a message to the client")
+    }
+}
+
+// The offset in UTF-16 code units of a 1-based column counted in bytes
+fn utf16_offset(line: &str, column: usize) -> usize {
+    line.get(..column - 1)
+        .map_or(column - 1, |before| before.encode_utf16().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf16_offsets() {
+        assert_eq!(utf16_offset("  src = ./foo;", 9), 8);
+        // `ü` is two bytes but one UTF-16 code unit, `𝔣` is four bytes but two code units
+        assert_eq!(utf16_offset("ü𝔣 ./foo", 8), 4);
+        // Columns past the end are kept as they are
+        assert_eq!(utf16_offset("", 3), 2);
+    }
+}
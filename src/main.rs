@@ -1,142 +1,694 @@
-use std::ffi::OsString;
-use crate::all_packages::AllPackages;
-use ignore::Walk;
-use std::collections::HashSet;
-use std::path::PathBuf;
 mod args;
-mod index;
-use args::Args;
-use clap::Parser;
-use index::GlobalIndex;
 
-mod all_packages;
-mod line_index;
+use anyhow::{bail, Result};
+use args::{Args, Command, CommitPer, Mode};
+use clap::Parser;
+use nix_spp::all_packages::AllPackages;
+use nix_spp::check_references::{self, check_references, Allowlist};
+use nix_spp::config::{unprefixed, Config};
+use nix_spp::diagnostic::{Code, Diagnostic, Format, Location, Reporter, Severity};
+use nix_spp::index::GlobalIndex;
+use nix_spp::baseline::{Baseline, Finding};
+use nix_spp::boundary::unit_boundary_violations;
+use nix_spp::batch::{self, BatchBy, BatchLimits};
+use nix_spp::filter::{self, AttrFilter};
+use nix_spp::diff::{Patch, Recorder};
+use nix_spp::git::Git;
+use nix_spp::migrate::{FileSystem, Migration, Mover};
+use nix_spp::naming::CaseFolded;
+use nix_spp::{convert, explain, lsp, migrate, report, unmigrate};
+use std::collections::HashSet;
+use std::path::Path;
 
-use anyhow::Result;
+fn main() -> Result<()> {
+    let cli = Args::parse();
+    let mut reporter = Reporter::new(cli.format, &cli.select, &cli.suppress);
+    let result = run(&cli, &mut reporter);
+    reporter.finish();
+    result
+}
 
-pub fn attr_shard_dir(attr: &String) -> OsString {
-    let str: String = attr.to_lowercase().chars().take(2).collect();
-    str.into()
+// The configuration file with command line arguments taking precedence
+fn config(cli: &Args) -> Result<Config> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::discover(cli.path())?,
+    };
+    if let Some(all_packages) = &cli.all_packages {
+        config.all_packages = all_packages.clone();
+    }
+    if let Some(layout) = cli.layout {
+        config.layout = layout;
+    }
+    if let Some(unit_dir) = &cli.unit_dir {
+        config.unit_dir = Some(unit_dir.clone());
+    }
+    if let Some(package_file) = &cli.package_file {
+        config.package_file = Some(package_file.clone());
+    }
+    if let Some(shard_length) = cli.shard_length {
+        config.shard_length = shard_length;
+    }
+    if let Some(cleanup_stop) = &cli.cleanup_stop {
+        config.cleanup_stop = cleanup_stop.clone();
+    }
+    config.exclude.extend(cli.exclude.iter().cloned());
+    Ok(config)
 }
 
-fn main() -> Result<()> {
-    let cli = Args::parse();
+fn run(cli: &Args, reporter: &mut Reporter) -> Result<()> {
+    // The patch is written to stdout, where it would be mixed with the diagnostics
+    if cli.diff && cli.format != Format::Text {
+        bail!("--diff can only be used with --format text");
+    }
+    // Same for the report and explanation
+    if cli.format != Format::Text {
+        match cli.command {
+            Some(Command::Report { .. }) => bail!("The report command can only be used with --format text"),
+            Some(Command::Explain { .. }) => bail!("The explain command can only be used with --format text"),
+            _ => {}
+        }
+    }
+    if let Some(Command::Lsp { .. }) = cli.command {
+        return lsp::run(cli.path(), &|| config(cli));
+    }
+    let config = config(cli)?;
+
+    let mut allowlist = match &cli.allowlist {
+        Some(path) => Allowlist::load(path)?,
+        None => Allowlist::default(),
+    };
+    for allowed in &config.allowed_references {
+        allowlist.insert(&allowed.file, &allowed.reference);
+    }
+
+    // Only the modes changing files can use git
+    let changes_files = match &cli.command {
+        Some(command) => matches!(command, Command::Unmigrate { .. }),
+        None => matches!(cli.mode, Some(Mode::Migrate | Mode::ConvertLayout)),
+    };
+    // A baseline written from only some of the findings would fail on the others
+    if cli.write_baseline && (filtered(cli) || cli.mode == Some(Mode::Migrate)) {
+        bail!("--write-baseline can't be used when migrating or with --attr, --attrs-from, --exclude-attr or --limit");
+    }
+    if cli.git && !changes_files {
+        bail!("--git can only be used with modes and commands that change files");
+    }
+    let git = if cli.git {
+        let git = Git::open(cli.path())?;
+        // Otherwise unrelated changes would end up in the migration commits
+        if git.has_staged_changes()? {
+            bail!("The git index already contains staged changes, commit or unstage them first");
+        }
+        Some(git)
+    } else {
+        None
+    };
+
+    let reference_index = GlobalIndex::new(cli.path(), &config.exclude)?;
+    reporter.report_all(reference_index.diagnostics.iter().cloned());
+
+    let mode = match &cli.command {
+        Some(Command::Unmigrate { attr, to, .. }) => {
+            return unmigrate(cli, &config, &reference_index, git.as_ref(), reporter, attr, to)
+        }
+        Some(Command::Report { output, .. }) => {
+            let ap = AllPackages::new(&config.all_packages_path(), &reference_index)?;
+            let rows = report::rows(&reference_index, &ap, &config);
+            print!("{}", report::render(*output, &ap, &rows));
+            return Ok(());
+        }
+        Some(Command::Explain { attr, .. }) => {
+            let ap = AllPackages::new(&config.all_packages_path(), &reference_index)?;
+            print!("{}", explain::explain(&reference_index, &ap, &config, attr)?);
+            return Ok(());
+        }
+        Some(Command::Lsp { .. }) => unreachable!("handled before indexing"),
+        // Required by clap without a subcommand
+        None => cli.mode.unwrap(),
+    };
+    match mode {
+        Mode::CheckReferences => {
+            // Unit directories only differing by case can't be checked out on every system
+            let collisions = CaseFolded::existing(&reference_index, &config);
+            reporter.report_all(collisions.diagnostics(Severity::Error));
+            // Nix rejects these. A broken all-packages.nix is already reported by the index
+            if let Ok(ap) = AllPackages::new(&config.all_packages_path(), &reference_index) {
+                reporter.report_all(ap.duplicate_diagnostics(Severity::Error));
+            }
+            check_references(&reference_index, &allowlist, reporter)?;
+            if reporter.error_count() > 0 {
+                bail!("Found {} error(s)", reporter.error_count());
+            }
+            return Ok(());
+        }
+        Mode::ConvertLayout => {
+            return convert_layout(cli, &config, &reference_index, git.as_ref(), reporter)
+        }
+        _ => {}
+    }
 
-    let reference_index = GlobalIndex::new(&cli.path);
+    let mut baseline = match &cli.baseline {
+        Some(_) if cli.write_baseline => Baseline::tolerate_all(),
+        Some(path) => Baseline::load(path)?,
+        None => Baseline::default(),
+    };
+    // Allowlisted references don't fail any mode
+    for invalid in check_references::reported(&reference_index, &allowlist) {
+        let severity = if invalid.kind.is_error() {
+            finding_severity(mode, &mut baseline, Finding::invalid_reference(invalid))
+        } else {
+            Severity::Warning
+        };
+        reporter.report(invalid.diagnostic(severity));
+    }
+    reporter.report_all(allowlist.unused(&reference_index));
+    for violation in unit_boundary_violations(&reference_index, &config) {
+        let severity = finding_severity(mode, &mut baseline, Finding::unit_boundary(&violation));
+        reporter.report(violation.diagnostic(severity));
+    }
+    // Existing unit directories colliding by case
+    let unit_dirs = CaseFolded::existing(&reference_index, &config);
+    let conflict_severity = if mode == Mode::Error {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    reporter.report_all(unit_dirs.diagnostics(conflict_severity));
 
     // println!("{:#?}", reference_index);
 
     // Function that parses all-packages.nix, returning a struct for every identifier assignment
     // that could be migrated, without looking at the file references
 
-    let mut ap = AllPackages::new(
-        &cli.path.join("pkgs/top-level/all-packages.nix"),
-        &reference_index,
-    );
-
-    'attrs: for (key, value) in ap.entries.to_owned() {
-        // if ! key.starts_with("zarc") {
-        //     continue
-        // }
-        //let mut movable_ancestor : PathBuf = value.path.clone();
-        let mut stack = vec![value.path.clone()];
-        let mut seen: HashSet<PathBuf> = HashSet::new();
-        seen.insert(value.path.clone());
-        let old_dir = value.path.parent().unwrap().to_path_buf();
-
-        while let Some(next) = stack.pop() {
-            for reference in reference_index
-                .path_indices
-                .get(&next)
-                .unwrap()
-                .references
-                .to_owned()
-            {
-                // println!("Reference: {:#?}", reference);
-                if !reference.movable_ancestor.starts_with(&old_dir) {
-                    eprintln!("Cannot move attribute {:?} pointing to file {:?}, because it transitively references file {:?} which in line {:?} contains a path reference {:?} which would break", key, value.path, next, reference.line, reference.text);
-                    continue 'attrs;
-                }
-                if seen.insert(reference.rel_to_root.clone()) {
-                    stack.push(reference.rel_to_root);
+    let mut ap = AllPackages::new(&config.all_packages_path(), &reference_index)?;
+    reporter.report_all(ap.diagnostics.iter().cloned());
+    reporter.report_all(ap.duplicate_diagnostics(conflict_severity));
+    reporter.report_all(migrate::shadowed_definitions(&reference_index, &ap, &config, conflict_severity));
+
+    let mut include = cli.attr.clone();
+    if let Some(path) = &cli.attrs_from {
+        include.extend(filter::load_patterns(path)?);
+    }
+    let attr_filter = AttrFilter::new(&include, &cli.exclude_attr)?;
+
+    let attrs: Vec<String> = ap
+        .entries_in_order()
+        .into_iter()
+        .map(|(attr, _)| attr.clone())
+        .filter(|attr| attr_filter.matches(attr))
+        .collect();
+    let mut migratable_count = 0;
+    let mut migrations = vec![];
+    for attr in attrs {
+        if cli.limit.is_some_and(|limit| migratable_count >= limit) {
+            break;
+        }
+        let migration = match migrate::analyse(&reference_index, &ap, &config, &attr) {
+            Ok(migration) => migration,
+            Err(mut diagnostic) => {
+                if diagnostic.code == Code::CaseCollision {
+                    diagnostic.severity = conflict_severity;
                 }
+                reporter.report(diagnostic);
+                continue;
+            }
+        };
+
+        let severity = match mode {
+            Mode::Migrate => Severity::Note,
+            _ => finding_severity(mode, &mut baseline, Finding::Migratable(attr.clone())),
+        };
+        let message = if let Mode::Migrate = mode {
+            format!("Migrating attribute {:?} pointing to file {:?} to {:?}", attr, migration.path, migration.unit_dir)
+        } else {
+            format!("Attribute {:?} pointing to file {:?} can be migrated to {:?}", attr, migration.path, migration.unit_dir)
+        };
+        reporter.report(Diagnostic::new(
+            severity,
+            Code::Migratable,
+            migration.location.clone(),
+            message,
+        ));
+        migratable_count += 1;
+        migrations.push(migration);
+    }
+
+    if let Some(path) = &cli.baseline {
+        if cli.write_baseline {
+            baseline.write(path)?;
+        } else {
+            // Not all attributes were checked if some were filtered out, and none when migrating
+            let migratable = !filtered(cli) && mode != Mode::Migrate;
+            for finding in baseline.stale(migratable) {
+                reporter.report(Diagnostic::new(
+                    Severity::Warning,
+                    Code::StaleBaselineEntry,
+                    Location::file(path),
+                    format!("Baseline entry \"{}\" isn't found anymore, remove it", finding),
+                ));
             }
         }
+    }
 
-        for file in seen.clone() {
-            for (referenced_by, index) in reference_index
-                .path_indices
-                .get(&file)
-                .unwrap()
-                .referenced_by
-                .to_owned()
-            {
-                let reference = reference_index
-                    .path_indices
-                    .get(&referenced_by)
-                    .unwrap()
-                    .references[index]
-                    .clone();
-                if referenced_by == PathBuf::from("./pkgs/top-level/all-packages.nix")
-                    && reference.line == value.line
-                {
-                    // println!("Attribute {:?} pointing to file {:?} is referenced by another file {:?} on line {:?}", key, value.path, referenced_by, reference.line);
-                    continue;
-                } else if seen.contains(&referenced_by) {
-                    // println!("Attribute {:?} pointing to file {:?} is referenced by another file {:?} on line {:?}", key, value.path, referenced_by, reference.line);
-                    continue;
-                } else {
-                    eprintln!("Cannot move attribute {:?} pointing to file {:?}, because one of its transitively referenced files {:?} is referenced by file {:?} on line {:?}", key, value.path, file, referenced_by, reference.line);
-                    continue 'attrs;
-                }
+    let limits = BatchLimits {
+        max_files: cli.max_files,
+        max_lines: cli.max_lines,
+    };
+    let batch_by = match cli.batch_by {
+        None if cli.max_files.is_some() || cli.max_lines.is_some() => Some(BatchBy::Size),
+        batch_by => batch_by,
+    };
+    let batches = batch::batches(migrations, batch_by, &limits);
+    let file_system = FileSystem {
+        root: cli.path().to_path_buf(),
+    };
+    let recorder = Recorder::new(cli.path());
+    let mover = mover(cli, git.as_ref(), &file_system, &recorder);
+    let mut rendered = HashSet::new();
+    let mut commits = vec![];
+    let batch_count = batches.len();
+    for (number, batch) in batches.into_iter().enumerate() {
+        if batch_by.is_some() {
+            let attrs: Vec<_> = batch.migrations.iter().map(|m| m.attr.as_str()).collect();
+            reporter.report(Diagnostic::new(
+                Severity::Note,
+                Code::Batch,
+                Location::file(&ap.path),
+                format!(
+                    "Batch {} of {} ({}) with {} files and {} lines: {}",
+                    number + 1,
+                    batch_count,
+                    batch.name,
+                    batch.file_count(),
+                    batch.line_count(),
+                    attrs.join(", ")
+                ),
+            ));
+        }
+        if !matches!(mode, Mode::Migrate) {
+            continue;
+        }
+
+        // Checks that all-packages.nix can be rendered before moving any files, such that a
+        // rendering problem doesn't leave moved files with their definitions still in place
+        for migration in &batch.migrations {
+            update_definitions(&mut ap, migration);
+        }
+        let check = ap.rendered();
+        for migration in &batch.migrations {
+            ap.restore(&migration.attr);
+            for (alias, _) in &migration.aliases {
+                ap.restore(alias);
             }
         }
+        if let Err(err) = check {
+            reporter.report(Diagnostic::new(
+                Severity::Error,
+                Code::MigrationFailed,
+                Location::file(&ap.path),
+                format!("Couldn't migrate batch {:?}, leaving it as is: {:#}", batch.name, err),
+            ));
+            continue;
+        }
 
-        let shard_dir = attr_shard_dir(&key);
-        let unit_dir = cli.path.join("pkgs/unit").join(shard_dir).join(&key);
-        std::fs::create_dir_all(&unit_dir).unwrap();
-        // println!("Moving attribute {:?} pointing to file {:?} to unit directory {:?}", key, value.path, unit_dir);
-
-        std::env::set_current_dir(&cli.path).unwrap();
-        for result in Walk::new(&old_dir) {
-            let old = result.unwrap().into_path();
-            let old_dir_ref_bys = &reference_index
-                .path_indices
-                .get(&old_dir)
-                .unwrap()
-                .referenced_by;
-            if old.is_dir() {
+        let mut migrated = vec![];
+        for migration in batch.migrations {
+            reporter.report_all(migration.diagnostics.iter().cloned());
+            if let Err(err) = migrate::apply(mover, &migration) {
+                reporter.report(Diagnostic::new(
+                    Severity::Error,
+                    Code::MigrationFailed,
+                    migration.location,
+                    format!("Couldn't migrate attribute {:?}, leaving it as is: {:#}", migration.attr, err),
+                ));
                 continue;
             }
-            if seen.contains(&old) {
-                // println!("Moving {:?} to {:?} because it's being transitively referenced", old, new);
-            } else if
-            // There can only be one reference from all-packages.nix, a bit hacky
-            old_dir_ref_bys.iter().filter(|(path, _)| path == &PathBuf::from("./pkgs/top-level/all-packages.nix")).count() == 1 &&
-                // And all the other references must come from the file itself
-                old_dir_ref_bys.iter().all(|(path, _)| path == &PathBuf::from("./pkgs/top-level/all-packages.nix") || path == &value.path) &&
-                // And the file to be moved must not be referenced from anywhere else
-                reference_index.path_indices.get(&old).unwrap().referenced_by.is_empty()
-            {
-                eprintln!("For attribute {:?}, only all-packages.nix and its file {:?} may reference the containing directory {:?}, which also contains the file {:?} which is not referenced from anywhere else. Also moving that to the unit directory", key, value.path, old_dir, old);
-            } else {
-                continue;
+            if !cli.diff {
+                cleanup(cli, &config, &migration, reporter)?;
             }
-            let base = old.strip_prefix(&old_dir).unwrap();
-            let mut new = unit_dir.join(base);
-            if old == value.path {
-                new.pop();
-                new.push("pkg-fun.nix");
+            update_definitions(&mut ap, &migration);
+            if let (Some(git), CommitPer::Attribute) = (&git, cli.commit_per) {
+                render(&ap, &mut rendered, reporter)?;
+                let subject = format!("{}: migrate to {}", migration.attr, unprefixed(&migration.unit_dir).display());
+                let message = format!("{}\n\n{}", subject, describe(&migration));
+                commits.push(commit(git, &ap, subject, &message)?);
             }
-            std::fs::create_dir_all(new.parent().unwrap()).unwrap();
-            // println!("Moving {:?} to {:?}", old, new);
-            std::fs::rename(&old, new).unwrap();
+            migrated.push(migration);
         }
 
-        ap.remove(&key);
+        if cli.diff {
+            continue;
+        }
+        // Rendering after every batch leaves the tree consistent in between
+        render(&ap, &mut rendered, reporter)?;
+        if let (Some(git), CommitPer::Batch) = (&git, cli.commit_per) {
+            if !migrated.is_empty() {
+                let subject = format!(
+                    "Migrate {} attribute(s) to {}",
+                    migrated.len(),
+                    unprefixed(&config.unit_dir()).display()
+                );
+                let mut message = format!("{}\n", subject);
+                for migration in &migrated {
+                    message += &format!("\n{}", describe(migration));
+                }
+                commits.push(commit(git, &ap, subject, &message)?);
+            }
+        }
     }
 
-    ap.render();
+    if let Some(git) = &git {
+        finish_commits(cli, git, &commits)?;
+    }
+    if cli.diff && matches!(mode, Mode::Migrate) {
+        let (contents, diagnostics) = ap.rendered()?;
+        reporter.report_all(diagnostics);
+        let mut patch = Patch::default();
+        patch.modify(&ap.path, &ap.original(), &contents);
+        print_patch(patch, &recorder);
+    }
 
+    if reporter.error_count() > 0 {
+        bail!("Found {} error(s)", reporter.error_count());
+    }
     Ok(())
 }
+
+// Removes the definition of a migrated attribute and points the ones sharing its file to the
+// unit directory
+fn update_definitions(ap: &mut AllPackages, migration: &Migration) {
+    ap.remove(&migration.attr);
+    for (alias, reference) in &migration.aliases {
+        ap.rewrite(alias, reference);
+    }
+}
+
+// Removes the directories a migration left empty, and reports the ones that can't be removed
+// because of files no package owns
+fn cleanup(cli: &Args, config: &Config, migration: &Migration, reporter: &mut Reporter) -> Result<()> {
+    let root = cli.path();
+    migrate::remove_empty_dirs(root, &migration.moves, &config.cleanup_stop())?;
+    let old_dir = migration.path.parent().unwrap();
+    let left: Vec<_> = migration
+        .unowned
+        .iter()
+        .filter(|file| root.join(file).exists())
+        .map(|file| unprefixed(file).display().to_string())
+        .collect();
+    if !left.is_empty() {
+        reporter.report(Diagnostic::new(
+            Severity::Warning,
+            Code::LeftoverFile,
+            Location::file(old_dir),
+            format!(
+                "Directory of migrated attribute {:?} can't be removed, because it still contains files no package owns: {}",
+                migration.attr,
+                left.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Whether only some of the attributes are analysed
+fn filtered(cli: &Args) -> bool {
+    !cli.attr.is_empty() || cli.attrs_from.is_some() || !cli.exclude_attr.is_empty() || cli.limit.is_some()
+}
+
+// Findings fail `--mode error` unless the baseline tolerates them
+fn finding_severity(mode: Mode, baseline: &mut Baseline, finding: Finding) -> Severity {
+    if baseline.contains(finding) || mode != Mode::Error {
+        Severity::Warning
+    } else {
+        Severity::Error
+    }
+}
+
+// Where files are moved: on disk, in git, or only recorded for a patch
+fn mover<'a>(
+    cli: &Args,
+    git: Option<&'a Git>,
+    file_system: &'a FileSystem,
+    recorder: &'a Recorder,
+) -> &'a dyn Mover {
+    match git {
+        Some(git) => git,
+        None if cli.diff => recorder,
+        None => file_system,
+    }
+}
+
+fn print_patch(mut patch: Patch, recorder: &Recorder) {
+    recorder.add_to(&mut patch);
+    print!("{}", patch);
+}
+
+// Writes all-packages.nix, reporting only diagnostics that weren't reported by earlier renders
+fn render(ap: &AllPackages, rendered: &mut HashSet<Diagnostic>, reporter: &mut Reporter) -> Result<()> {
+    for diagnostic in ap.render()? {
+        if rendered.insert(diagnostic.clone()) {
+            reporter.report(diagnostic);
+        }
+    }
+    Ok(())
+}
+
+// The commit message lines describing a single migration
+fn describe(migration: &Migration) -> String {
+    format!(
+        "- {}: moved from {} to {}\n",
+        migration.attr,
+        unprefixed(&migration.path).display(),
+        unprefixed(&migration.unit_dir).display()
+    )
+}
+
+// Stages all-packages.nix and commits, returning the commit id and subject
+fn commit(git: &Git, ap: &AllPackages, subject: String, message: &str) -> Result<(String, String)> {
+    git.add(&ap.path)?;
+    let id = git.commit(message)?;
+    Ok((id, subject))
+}
+
+// Records the migration commits in .git-blame-ignore-revs if requested
+fn finish_commits(cli: &Args, git: &Git, commits: &[(String, String)]) -> Result<()> {
+    if cli.blame_ignore_revs && !commits.is_empty() {
+        git.add_blame_ignore_revs(commits)?;
+        git.commit(&format!(
+            "Add {} migration commit(s) to {}",
+            commits.len(),
+            nix_spp::git::BLAME_IGNORE_REVS
+        ))?;
+    }
+    Ok(())
+}
+
+fn convert_layout(
+    cli: &Args,
+    config: &Config,
+    reference_index: &GlobalIndex,
+    git: Option<&Git>,
+    reporter: &mut Reporter,
+) -> Result<()> {
+    let (conversions, diagnostics) = convert::analyse(reference_index, config)?;
+    reporter.report_all(diagnostics);
+    let file_system = FileSystem {
+        root: cli.path().to_path_buf(),
+    };
+    let recorder = Recorder::new(cli.path());
+    let mover = mover(cli, git, &file_system, &recorder);
+    let mut commits = vec![];
+    for conversion in conversions {
+        let location = Location::file(&conversion.from);
+        match convert::apply(mover, &conversion) {
+            Ok(()) => reporter.report(Diagnostic::new(
+                Severity::Note,
+                Code::Converted,
+                location,
+                format!("Moved package {:?} to {:?}", conversion.attr, conversion.to),
+            )),
+            Err(err) => {
+                reporter.report(Diagnostic::new(
+                    Severity::Error,
+                    Code::MigrationFailed,
+                    location,
+                    format!("Couldn't convert package {:?}, leaving it as is: {:#}", conversion.attr, err),
+                ));
+                continue;
+            }
+        }
+        if !cli.diff {
+            migrate::remove_empty_dirs(cli.path(), &conversion.moves, &config.cleanup_stop())?;
+        }
+        if let Some(git) = git {
+            // Layout conversions only move files, so there's nothing to batch up
+            let subject = format!("{}: move to {}", conversion.attr, unprefixed(&conversion.to).display());
+            let message = format!("{}\n\nMoved from {}\n", subject, unprefixed(&conversion.from).display());
+            commits.push((git.commit(&message)?, subject));
+        }
+    }
+    if let Some(git) = git {
+        finish_commits(cli, git, &commits)?;
+    }
+    if cli.diff {
+        print_patch(Patch::default(), &recorder);
+    }
+
+    if reporter.error_count() > 0 {
+        bail!("Found {} error(s)", reporter.error_count());
+    }
+    Ok(())
+}
+
+fn unmigrate(
+    cli: &Args,
+    config: &Config,
+    reference_index: &GlobalIndex,
+    git: Option<&Git>,
+    reporter: &mut Reporter,
+    attr: &str,
+    to: &Path,
+) -> Result<()> {
+    let ap = AllPackages::new(&config.all_packages_path(), reference_index)?;
+    let unmigration = match unmigrate::analyse(reference_index, &ap, config, attr, to) {
+        Ok(unmigration) => unmigration,
+        Err(diagnostics) => {
+            reporter.report_all(diagnostics);
+            bail!("Found {} error(s)", reporter.error_count());
+        }
+    };
+
+    let file_system = FileSystem {
+        root: cli.path().to_path_buf(),
+    };
+    let recorder = Recorder::new(cli.path());
+    let mover = mover(cli, git, &file_system, &recorder);
+    if cli.diff {
+        unmigrate::apply(mover, &unmigration, |_| Ok(()))?;
+        let mut patch = Patch::default();
+        patch.modify(&ap.path, &ap.original(), &unmigration.all_packages);
+        print_patch(patch, &recorder);
+        return Ok(());
+    }
+    unmigrate::apply(mover, &unmigration, |contents| ap.write(contents))?;
+    // An empty unit directory would still shadow the new definition
+    migrate::remove_empty_dirs(cli.path(), &unmigration.moves, &config.unit_dir())?;
+    reporter.report(Diagnostic::new(
+        Severity::Note,
+        Code::Converted,
+        Location::file(&unmigration.from),
+        format!("Moved package {:?} back to {:?}", attr, unmigration.to),
+    ));
+    if let Some(git) = git {
+        let subject = format!("{}: move back to {}", attr, unprefixed(&unmigration.to).display());
+        let message = format!("{}\n\nMoved from {}\n", subject, unprefixed(&unmigration.from).display());
+        let commit = commit(git, &ap, subject, &message)?;
+        finish_commits(cli, git, &[commit])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix_spp::config::{Layout, CONFIG_FILE};
+
+    #[test]
+    fn command_line_over_configuration_file() {
+        let root = std::env::temp_dir().join(format!("nix-spp-main-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join(CONFIG_FILE),
+            "layout = \"by-name\"\nshard-length = 3\ncleanup-stop = \"pkgs/tools\"\nexclude = [\"a/**\"]\n",
+        )
+        .unwrap();
+        let parse = |args: &[&str]| {
+            let root = root.to_str().unwrap();
+            config(&Args::parse_from(["nix-spp", "--mode", "warn"].iter().chain(args).chain([&root])))
+        };
+
+        let from_file = parse(&[]).unwrap();
+        assert_eq!(from_file.layout, Layout::ByName);
+        assert_eq!(from_file.shard_length, 3);
+        assert_eq!(from_file.exclude, ["a/**"]);
+
+        let overridden = parse(&["--layout", "unit", "--shard-length", "1", "--cleanup-stop", "pkgs", "--exclude", "b/**"]).unwrap();
+        assert_eq!(overridden.layout, Layout::Unit);
+        assert_eq!(overridden.shard_length, 1);
+        assert_eq!(overridden.cleanup_stop(), Path::new("./pkgs"));
+        // Exclusions add up
+        assert_eq!(overridden.exclude, ["a/**", "b/**"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn git(root: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git").current_dir(root).args(args).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    // Migrates two packages in a fresh repository, returning the subjects of the new commits
+    fn migrated_commits(name: &str, args: &[&str]) -> Vec<String> {
+        let root = std::env::temp_dir().join(format!("nix-spp-main-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, contents) in [
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  bar = callPackage ../tools/bar { };\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+        ] {
+            std::fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            std::fs::write(root.join(path), contents).unwrap();
+        }
+        git(&root, &["init", "--quiet"]);
+        git(&root, &["config", "user.name", "Test"]);
+        git(&root, &["config", "user.email", "test@example.org"]);
+        git(&root, &["config", "commit.gpgsign", "false"]);
+        git(&root, &["add", "--all"]);
+        git(&root, &["commit", "--quiet", "--message", "Initial"]);
+
+        let root_arg = root.to_str().unwrap();
+        let cli = Args::parse_from(["nix-spp", "--mode", "migrate", "--git"].iter().chain(args).chain([&root_arg]));
+        let mut reporter = Reporter::new(Format::Sarif, &[], &[]);
+        run(&cli, &mut reporter).unwrap();
+        assert_eq!(git(&root, &["status", "--porcelain"]), "");
+        let subjects = git(&root, &["log", "--format=%s", "--reverse"])
+            .lines()
+            .skip(1)
+            .map(str::to_string)
+            .collect();
+        if cli.blame_ignore_revs {
+            // Every commit but the initial one and the one adding the file itself
+            let revs = std::fs::read_to_string(root.join(nix_spp::git::BLAME_IGNORE_REVS)).unwrap();
+            for id in git(&root, &["log", "--format=%H", "HEAD~1", "^HEAD~3"]).lines() {
+                assert!(revs.contains(&format!("\n{}\n", id)), "{} not in {:?}", id, revs);
+            }
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+        subjects
+    }
+
+    #[test]
+    fn commit_per_attribute() {
+        assert_eq!(
+            migrated_commits("attribute", &["--blame-ignore-revs"]),
+            [
+                "foo: migrate to pkgs/unit/fo/foo",
+                "bar: migrate to pkgs/unit/ba/bar",
+                "Add 2 migration commit(s) to .git-blame-ignore-revs",
+            ]
+        );
+    }
+
+    #[test]
+    fn commit_per_batch() {
+        assert_eq!(
+            migrated_commits("batch", &["--commit-per", "batch", "--max-files", "10"]),
+            ["Migrate 2 attribute(s) to pkgs/unit"]
+        );
+    }
+}
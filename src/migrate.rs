@@ -0,0 +1,597 @@
+use crate::all_packages::AllPackages;
+use crate::config::{relative_reference, Config};
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
+use crate::index::GlobalIndex;
+use crate::naming::{case_collision, check_name};
+use crate::ownership::{Owner, Ownership};
+use anyhow::{bail, Context, Error, Result};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+// Everything needed to migrate a single all-packages.nix attribute to its unit directory
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub attr: String,
+    // Where the attribute is defined in all-packages.nix
+    pub location: Location,
+    // The file the attribute's callPackage refers to
+    pub path: PathBuf,
+    // The number of lines of the definition in all-packages.nix
+    pub lines: usize,
+    pub unit_dir: PathBuf,
+    // The package file and all files it transitively references
+    pub closure: HashSet<PathBuf>,
+    // Pairs of old and new paths, relative to the root
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    // Comments attached to the definition, which are moved to the top of the package file
+    pub comments: Vec<String>,
+    // Files in the old directory that no package owns, which stay behind
+    pub unowned: Vec<PathBuf>,
+    // Other attributes with the same package file, whose definitions are rewritten to refer to
+    // the package file in the unit directory, with the new path reference
+    pub aliases: Vec<(String, String)>,
+    // Notes about files that are moved along even though they're not in the closure
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+// Determines whether an attribute can be migrated without breaking any references, and if so
+// which files need to be moved. If it can't be migrated, the returned diagnostic explains why.
+// This doesn't change anything on disk
+pub fn analyse(
+    index: &GlobalIndex,
+    all_packages: &AllPackages,
+    config: &Config,
+    attr: &str,
+) -> Result<Migration, Diagnostic> {
+    let value = all_packages.entries.get(attr).unwrap();
+    let all_packages_path = &all_packages.path;
+    let location = Location::new(all_packages_path, value.line, value.column);
+    if let Err(reason) = check_name(attr) {
+        return Err(Diagnostic::new(
+            Severity::Warning,
+            Code::UnsupportedName,
+            location,
+            format!("Cannot move attribute {:?}, because {}", attr, reason),
+        ));
+    }
+    // E.g. a definition rewritten to refer to the package file of another attribute
+    if value.path.starts_with(config.unit_dir()) {
+        return Err(Diagnostic::new(
+            Severity::Note,
+            Code::ExistingUnitDir,
+            location,
+            format!("Cannot move attribute {:?}, because its file {:?} is already in the unit directory", attr, value.path),
+        ));
+    }
+    // Moving into it could merge two packages or overwrite the package file
+    let unit_dir = config.unit_dir_for(attr);
+    if unit_exists(index, &unit_dir) {
+        return Err(Diagnostic::new(
+            Severity::Warning,
+            Code::ExistingUnitDir,
+            location,
+            format!("Cannot move attribute {:?}, because its unit directory {:?} already exists", attr, unit_dir),
+        ));
+    }
+    // Only one of them could be checked out on case-insensitive filesystems. Of attributes whose
+    // names only differ by case, the first one that can be migrated is
+    let migrated_first = all_packages
+        .case_variants(attr)
+        .iter()
+        .filter(|other| analyse(index, all_packages, config, other).is_ok());
+    if let Some(other) = case_collision(index, config, attr, migrated_first) {
+        return Err(Diagnostic::new(
+            Severity::Warning,
+            Code::CaseCollision,
+            location,
+            format!(
+                "Cannot move attribute {:?}, because its unit directory {:?} would only differ by case from {:?}",
+                attr, unit_dir, other
+            ),
+        ));
+    }
+    let sharing = all_packages.sharing(attr);
+    let canonical = all_packages.canonical(attr);
+    if canonical != attr {
+        return Err(Diagnostic::new(
+            Severity::Note,
+            Code::SharedPackageFile,
+            location,
+            format!(
+                "Cannot move attribute {:?}, because its file {:?} is shared by attributes {}, of which {:?} is migrated instead",
+                attr,
+                value.path,
+                quoted(sharing),
+                canonical
+            ),
+        ));
+    }
+
+    //let mut movable_ancestor : PathBuf = value.path.clone();
+    let mut stack = vec![value.path.clone()];
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    seen.insert(value.path.clone());
+    let old_dir = value.path.parent().unwrap().to_path_buf();
+
+    while let Some(next) = stack.pop() {
+        for reference in &index.path_indices.get(&next).unwrap().references {
+            // println!("Reference: {:#?}", reference);
+            if !reference.movable_ancestor.starts_with(&old_dir) {
+                return Err(Diagnostic::new(
+                    Severity::Note,
+                    Code::OutgoingReference,
+                    location,
+                    format!("Cannot move attribute {:?} pointing to file {:?}, because it transitively references a path outside its directory", attr, value.path),
+                )
+                .with_related(
+                    Location::new(&next, reference.line, reference.column),
+                    format!("Path reference {:?} would break", reference.text),
+                ));
+            }
+            if seen.insert(reference.rel_to_root.clone()) {
+                stack.push(reference.rel_to_root.clone());
+            }
+        }
+    }
+
+    // The two allowed kinds of referencers are kept apart
+    #[allow(clippy::if_same_then_else)]
+    for file in &seen {
+        for (referenced_by, reference_index) in &index.path_indices.get(file).unwrap().referenced_by {
+            let reference =
+                &index.path_indices.get(referenced_by).unwrap().references[*reference_index];
+            if from_definition(all_packages, attr, referenced_by, reference.offset, file) {
+                // println!("Attribute {:?} pointing to file {:?} is referenced by another file {:?} on line {:?}", attr, value.path, referenced_by, reference.line);
+                continue;
+            } else if seen.contains(referenced_by) {
+                // println!("Attribute {:?} pointing to file {:?} is referenced by another file {:?} on line {:?}", attr, value.path, referenced_by, reference.line);
+                continue;
+            } else {
+                return Err(Diagnostic::new(
+                    Severity::Note,
+                    Code::IncomingReference,
+                    location,
+                    format!("Cannot move attribute {:?} pointing to file {:?}, because one of its transitively referenced files {:?} is referenced from outside", attr, value.path, file),
+                )
+                .with_related(
+                    Location::new(referenced_by, reference.line, reference.column),
+                    format!("Path reference {:?} would break", reference.text),
+                ));
+            }
+        }
+    }
+
+    // Every file in the directory is either part of the closure, moved along as one of the
+    // package's own files, or stays behind
+    let ownership = Ownership::new(index, all_packages, &old_dir);
+    let mut moves = vec![];
+    let mut unowned = vec![];
+    let mut diagnostics = vec![];
+    for (old, owner) in &ownership.files {
+        if seen.contains(old) {
+            // println!("Moving {:?} to {:?} because it's being transitively referenced", old, new);
+        } else if ownership.moves_with(old, attr) {
+            diagnostics.push(Diagnostic::new(
+                Severity::Note,
+                Code::MovedSibling,
+                Location::file(old),
+                format!("For attribute {:?}, this file {}. Also moving it to the unit directory", attr, owner),
+            ));
+        } else {
+            // Files nothing refers to would be stranded, the others are used from where they are
+            let severity = match owner {
+                Owner::Unowned => {
+                    unowned.push(old.clone());
+                    Severity::Warning
+                }
+                _ => Severity::Note,
+            };
+            diagnostics.push(Diagnostic::new(
+                severity,
+                Code::LeftoverFile,
+                Location::file(old),
+                format!("For attribute {:?}, leaving this file in {:?}, because it {}", attr, old_dir, owner),
+            ));
+            continue;
+        }
+        let base = old.strip_prefix(&old_dir).unwrap();
+        let mut new = unit_dir.join(base);
+        if *old == value.path {
+            new.pop();
+            new.push(config.package_file());
+        }
+        moves.push((old.clone(), new));
+    }
+
+    // Each alias refers to the new package file from all-packages.nix
+    let package_file = unit_dir.join(config.package_file());
+    let reference = relative_reference(all_packages_path.parent().unwrap(), &package_file);
+    let mut aliases = vec![];
+    for alias in sharing.iter().filter(|alias| *alias != attr) {
+        diagnostics.push(Diagnostic::new(
+            Severity::Note,
+            Code::SharedPackageFile,
+            location.clone(),
+            format!("Attribute {:?} shares the file of attribute {:?}, rewriting its definition to refer to {:?}", alias, attr, reference),
+        ));
+        aliases.push((alias.clone(), reference.clone()));
+    }
+
+    Ok(Migration {
+        attr: attr.to_string(),
+        location,
+        path: value.path.clone(),
+        lines: value.end_line - value.line + 1,
+        unit_dir,
+        closure: seen,
+        moves,
+        comments: value.comments.clone(),
+        unowned,
+        aliases,
+        diagnostics,
+    })
+}
+
+// Whether a reference to a file in the closure comes from the definition of the attribute, or
+// from the definition of an attribute sharing its package file, which can be rewritten as long
+// as it refers to the package file itself
+pub fn from_definition(
+    all_packages: &AllPackages,
+    attr: &str,
+    source: &Path,
+    offset: usize,
+    file: &Path,
+) -> bool {
+    source == all_packages.path
+        && all_packages.entry_at(offset).is_some_and(|(other, entry)| {
+            other == attr || (all_packages.sharing(attr).contains(other) && entry.path == file)
+        })
+}
+
+fn quoted(attrs: &[String]) -> String {
+    attrs
+        .iter()
+        .map(|attr| format!("{:?}", attr))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn unit_exists(index: &GlobalIndex, unit_dir: &Path) -> bool {
+    index.path_indices.contains_key(unit_dir) || index.root.join(unit_dir).exists()
+}
+
+// Definitions in all-packages.nix for attributes that also have a unit directory, where it's
+// unclear which of the two ends up being used
+pub fn shadowed_definitions(
+    index: &GlobalIndex,
+    all_packages: &AllPackages,
+    config: &Config,
+    severity: Severity,
+) -> Vec<Diagnostic> {
+    let definitions = all_packages
+        .entries_in_order()
+        .into_iter()
+        .map(|(attr, entry)| (attr.as_str(), entry.line, entry.column))
+        .chain(
+            all_packages
+                .blocked
+                .iter()
+                .map(|blocked| (blocked.attr.as_str(), blocked.line, blocked.column)),
+        );
+    let mut diagnostics = vec![];
+    for (attr, line, column) in definitions {
+        // Attribute paths and strings can't be unit directory names
+        if check_name(attr).is_err() {
+            continue;
+        }
+        let unit_dir = config.unit_dir_for(attr);
+        if unit_exists(index, &unit_dir) {
+            diagnostics.push(Diagnostic::new(
+                severity,
+                Code::ShadowedDefinition,
+                Location::new(&all_packages.path, line, column),
+                format!("Attribute {:?} is defined both here and in the unit directory {:?}", attr, unit_dir),
+            ));
+        }
+    }
+    diagnostics.sort_by_key(|diagnostic| diagnostic.location.line);
+    diagnostics
+}
+
+// Removes the directories files were moved out of if they're empty now, and then their parents,
+// up to but excluding the stop directory. Git doesn't track directories, so this is needed either
+// way. Returns the removed directories
+pub fn remove_empty_dirs(root: &Path, moves: &[(PathBuf, PathBuf)], stop: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    for (old, _) in moves {
+        let mut dir = old.parent();
+        while let Some(current) = dir {
+            if !current.starts_with(stop) || current == stop {
+                break;
+            }
+            let absolute = root.join(current);
+            let mut entries = match std::fs::read_dir(&absolute) {
+                Ok(entries) => entries,
+                // Already removed while cleaning up after another file
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    dir = current.parent();
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Couldn't read directory {:?}", absolute))
+                }
+            };
+            if entries.next().is_some() {
+                break;
+            }
+            std::fs::remove_dir(&absolute)
+                .with_context(|| format!("Couldn't remove directory {:?}", absolute))?;
+            removed.push(current.to_path_buf());
+            dir = current.parent();
+        }
+    }
+    Ok(removed)
+}
+
+// How files are moved, with paths relative to the project root
+pub trait Mover {
+    fn move_file(&self, old: &Path, new: &Path) -> Result<()>;
+    // Adds text to the beginning of a file
+    fn prepend(&self, path: &Path, text: &str) -> Result<()>;
+}
+
+// Moves files with plain renames
+pub struct FileSystem {
+    pub root: PathBuf,
+}
+
+impl Mover for FileSystem {
+    fn move_file(&self, old: &Path, new: &Path) -> Result<()> {
+        let (old, new) = (self.root.join(old), self.root.join(new));
+        // A rename would silently replace it
+        if new.exists() {
+            bail!("Couldn't move {:?} to {:?}, because it already exists", old, new);
+        }
+        std::fs::create_dir_all(new.parent().unwrap())
+            .with_context(|| format!("Couldn't create directory {:?}", new.parent().unwrap()))?;
+        std::fs::rename(&old, &new).with_context(|| format!("Couldn't move {:?} to {:?}", old, new))
+    }
+
+    fn prepend(&self, path: &Path, text: &str) -> Result<()> {
+        let path = self.root.join(path);
+        let contents =
+            read_to_string(&path).with_context(|| format!("Couldn't read {:?}", path))?;
+        std::fs::write(&path, text.to_string() + &contents)
+            .with_context(|| format!("Couldn't write {:?}", path))
+    }
+}
+
+// Moves the files of a migration and the comments of its definition. Either all files are moved
+// or none
+pub fn apply(mover: &dyn Mover, migration: &Migration) -> Result<()> {
+    move_files(mover, &migration.moves)?;
+    if migration.comments.is_empty() {
+        return Ok(());
+    }
+    let (_, package_file) = migration
+        .moves
+        .iter()
+        .find(|(old, _)| old == &migration.path)
+        .unwrap();
+    let text = migration.comments.join("\n") + "\n";
+    mover
+        .prepend(package_file, &text)
+        .map_err(|err| undo_moves(mover, &migration.moves, err))
+}
+
+// Moves all files, undoing the already done moves if one of them fails, such that a package
+// is either moved completely or not at all
+pub(crate) fn move_files(mover: &dyn Mover, moves: &[(PathBuf, PathBuf)]) -> Result<()> {
+    for (done, (old, new)) in moves.iter().enumerate() {
+        if let Err(err) = mover.move_file(old, new) {
+            return Err(undo_moves(mover, &moves[..done], err));
+        }
+    }
+    Ok(())
+}
+
+// Moves files back after an error, returning the error with any failures to do so
+pub(crate) fn undo_moves(mover: &dyn Mover, moves: &[(PathBuf, PathBuf)], mut err: Error) -> Error {
+    for (old, new) in moves.iter().rev() {
+        if let Err(undo_err) = mover.move_file(new, old) {
+            err = err.context(format!(
+                "Couldn't move {:?} back to {:?}, this needs to be fixed manually: {:#}",
+                new, old, undo_err
+            ));
+        }
+    }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    fn analysed(project: &TempProject, attrs: &[&str]) -> Vec<Result<PathBuf, (Code, String)>> {
+        let index = project.index();
+        let config = Config::default();
+        let all_packages = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        attrs
+            .iter()
+            .map(|attr| {
+                analyse(&index, &all_packages, &config, attr)
+                    .map(|migration| migration.unit_dir)
+                    .map_err(|diagnostic| (diagnostic.code, diagnostic.message))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn case_collisions() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  Foo = callPackage ../tools/Foo { };\n  Bar = callPackage ../tools/foo { };\n  bar = callPackage ../tools/bar { };\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/Foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+        ]);
+        assert_eq!(
+            analysed(&project, &["foo", "Foo", "Bar", "bar"]),
+            [
+                Ok(PathBuf::from("./pkgs/unit/fo/foo")),
+                Err((
+                    Code::CaseCollision,
+                    "Cannot move attribute \"Foo\", because its unit directory \"./pkgs/unit/fo/Foo\" would only differ by case from \"./pkgs/unit/fo/foo\"".to_string()
+                )),
+                Err((
+                    Code::SharedPackageFile,
+                    "Cannot move attribute \"Bar\", because its file \"./pkgs/tools/foo/default.nix\" is shared by attributes \"foo\", \"Bar\", of which \"foo\" is migrated instead".to_string()
+                )),
+                // `Bar` isn't migrated, so it doesn't collide
+                Ok(PathBuf::from("./pkgs/unit/ba/bar")),
+            ]
+        );
+    }
+
+    #[test]
+    fn definitions_on_one_line() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { }; bar = callPackage ../tools/bar { };\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+        ]);
+        assert_eq!(
+            analysed(&project, &["foo", "bar"]),
+            [
+                Ok(PathBuf::from("./pkgs/unit/fo/foo")),
+                Ok(PathBuf::from("./pkgs/unit/ba/bar")),
+            ]
+        );
+    }
+
+    #[test]
+    fn existing_unit_dirs() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  bar = callPackage ../tools/bar { };\n  baz = 1;\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/ba/baz/pkg-fun.nix", "{ }: { }\n"),
+        ]);
+        assert_eq!(
+            analysed(&project, &["foo", "bar"]),
+            [
+                Err((
+                    Code::ExistingUnitDir,
+                    "Cannot move attribute \"foo\", because its unit directory \"./pkgs/unit/fo/foo\" already exists".to_string()
+                )),
+                Ok(PathBuf::from("./pkgs/unit/ba/bar")),
+            ]
+        );
+
+        let index = project.index();
+        let config = Config::default();
+        let all_packages = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        let shadowed: Vec<_> =
+            shadowed_definitions(&index, &all_packages, &config, Severity::Warning)
+                .into_iter()
+                .map(|diagnostic| (diagnostic.location.line, diagnostic.message))
+                .collect();
+        assert_eq!(
+            shadowed,
+            [
+                (
+                    Some(3),
+                    "Attribute \"foo\" is defined both here and in the unit directory \"./pkgs/unit/fo/foo\"".to_string()
+                ),
+                (
+                    Some(5),
+                    "Attribute \"baz\" is defined both here and in the unit directory \"./pkgs/unit/ba/baz\"".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn moves_dont_overwrite() {
+        let project = TempProject::new(&[("a/x.nix", "a"), ("b/x.nix", "b"), ("a/y.nix", "y")]);
+        let mover = FileSystem {
+            root: project.root.clone(),
+        };
+        let moves = [
+            (PathBuf::from("./a/y.nix"), PathBuf::from("./b/y.nix")),
+            (PathBuf::from("./a/x.nix"), PathBuf::from("./b/x.nix")),
+        ];
+        let error = move_files(&mover, &moves).unwrap_err();
+        assert!(format!("{:#}", error).contains("because it already exists"));
+        // The first move is undone
+        assert!(project.exists("a/y.nix") && !project.exists("b/y.nix"));
+        assert!(project.exists("a/x.nix") && project.exists("b/x.nix"));
+    }
+
+    fn moves(paths: &[&str]) -> Vec<(PathBuf, PathBuf)> {
+        paths
+            .iter()
+            .map(|path| (PathBuf::from(path), PathBuf::from("./pkgs/unit/fo/foo/pkg-fun.nix")))
+            .collect()
+    }
+
+    #[test]
+    fn removes_nested_empty_dirs() {
+        let project = TempProject::new(&[("pkgs/tools/misc/bar/default.nix", "")]);
+        std::fs::create_dir_all(project.root.join("pkgs/tools/misc/foo/patches/old")).unwrap();
+        let removed = remove_empty_dirs(
+            &project.root,
+            &moves(&["./pkgs/tools/misc/foo/patches/old/fix.patch", "./pkgs/tools/misc/foo/default.nix"]),
+            Path::new("./pkgs"),
+        )
+        .unwrap();
+        assert_eq!(
+            removed,
+            [
+                PathBuf::from("./pkgs/tools/misc/foo/patches/old"),
+                PathBuf::from("./pkgs/tools/misc/foo/patches"),
+                PathBuf::from("./pkgs/tools/misc/foo"),
+            ]
+        );
+        // Still contains `bar`
+        assert!(project.exists("pkgs/tools/misc"));
+    }
+
+    #[test]
+    fn stops_at_cleanup_stop() {
+        let project = TempProject::new(&[]);
+        std::fs::create_dir_all(project.root.join("pkgs/tools/foo")).unwrap();
+        let removed =
+            remove_empty_dirs(&project.root, &moves(&["./pkgs/tools/foo/default.nix"]), Path::new("./pkgs/tools"))
+                .unwrap();
+        assert_eq!(removed, [PathBuf::from("./pkgs/tools/foo")]);
+        assert!(project.exists("pkgs/tools"));
+
+        // Directories outside of the stop are left alone
+        std::fs::create_dir_all(project.root.join("lib/foo")).unwrap();
+        let removed =
+            remove_empty_dirs(&project.root, &moves(&["./lib/foo/default.nix"]), Path::new("./pkgs")).unwrap();
+        assert!(removed.is_empty());
+        assert!(project.exists("lib/foo"));
+    }
+
+    #[test]
+    fn keeps_non_empty_dirs() {
+        let project = TempProject::new(&[("pkgs/tools/foo/update.sh", "")]);
+        let removed =
+            remove_empty_dirs(&project.root, &moves(&["./pkgs/tools/foo/default.nix"]), Path::new("./pkgs")).unwrap();
+        assert!(removed.is_empty());
+        assert!(project.exists("pkgs/tools/foo/update.sh"));
+    }
+}
@@ -0,0 +1,188 @@
+// Which attribute names get a unit directory, and detecting unit directories that only differ by
+// case, which collide on case-insensitive filesystems like the default ones of macOS and Windows.
+//
+// The policy for names is:
+// - Only ASCII letters, digits, `_` and `-` are allowed, since other characters may be
+//   normalised differently by filesystems, e.g. NFD on macOS
+// - Names can't start with `_`, which by convention marks attributes that aren't packages
+// - Names shorter than the shard length have the whole lowercased name as the shard, e.g.
+//   `pkgs/unit/r/r` for `r`
+use crate::config::Config;
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
+use crate::index::GlobalIndex;
+use std::collections::BTreeMap;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+
+// Checks an attribute name against the policy, returning why it's not supported otherwise
+pub fn check_name(attr: &str) -> Result<(), String> {
+    if let Some(c) = attr
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && *c != '_' && *c != '-')
+    {
+        return Err(format!(
+            "it contains {:?}, only ASCII letters, digits, `_` and `-` are supported",
+            c
+        ));
+    }
+    if attr.starts_with('_') {
+        return Err("it starts with `_`, which is reserved for attributes that aren't packages".to_string());
+    }
+    Ok(())
+}
+
+fn fold(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+// Unit directories by their case-folded path, to find ones that only differ by case
+#[derive(Debug, Default)]
+pub struct CaseFolded {
+    dirs: BTreeMap<String, Vec<PathBuf>>,
+}
+
+impl CaseFolded {
+    // All package directories that exist in the unit directory
+    pub fn existing(index: &GlobalIndex, config: &Config) -> CaseFolded {
+        let unit_dir = config.unit_dir();
+        let depth = unit_dir.components().count();
+        let mut folded = CaseFolded::default();
+        for (path, path_index) in &index.path_indices {
+            if path_index.is_dir
+                && path.starts_with(&unit_dir)
+                && path.components().count() == depth + 2
+            {
+                folded.insert(path);
+            }
+        }
+        folded
+    }
+
+    fn insert(&mut self, dir: &Path) {
+        let dirs = self.dirs.entry(fold(dir)).or_default();
+        if !dirs.iter().any(|other| other == dir) {
+            dirs.push(dir.to_path_buf());
+        }
+    }
+
+    // A directory that only differs by case from the given one
+    pub fn collision(&self, dir: &Path) -> Option<&PathBuf> {
+        self.dirs
+            .get(&fold(dir))?
+            .iter()
+            .find(|other| other.as_path() != dir)
+    }
+
+    // Diagnostics for all groups of directories that only differ by case
+    pub fn diagnostics(&self, severity: Severity) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        for dirs in self.dirs.values() {
+            let mut dirs = dirs.clone();
+            dirs.sort();
+            if let [first, others @ ..] = &dirs[..] {
+                for other in others {
+                    diagnostics.push(collision_diagnostic(severity, other, first));
+                }
+            }
+        }
+        diagnostics
+    }
+}
+
+// A unit directory that only differs by case from the one of the attribute: an existing one, or
+// the one of another attribute that is migrated first
+pub fn case_collision<'a>(
+    index: &GlobalIndex,
+    config: &Config,
+    attr: &str,
+    migrated_first: impl IntoIterator<Item = &'a String>,
+) -> Option<PathBuf> {
+    let unit_dir = config.unit_dir_for(attr);
+    let shard_dir = unit_dir.parent().unwrap();
+    let mut folded = CaseFolded::default();
+    // Like the check for an existing unit directory, this looks at the disk, since the index
+    // can't list the directories in the shard directory
+    if let Ok(entries) = read_dir(index.root.join(shard_dir)) {
+        for entry in entries.flatten() {
+            folded.insert(&shard_dir.join(entry.file_name()));
+        }
+    }
+    for other in migrated_first {
+        folded.insert(&config.unit_dir_for(other));
+    }
+    folded.collision(&unit_dir).cloned()
+}
+
+fn collision_diagnostic(severity: Severity, dir: &Path, other: &Path) -> Diagnostic {
+    Diagnostic::new(
+        severity,
+        Code::CaseCollision,
+        Location::file(dir),
+        format!(
+            "Unit directory {:?} only differs by case from {:?}, which collides on case-insensitive filesystems",
+            dir, other
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    #[test]
+    fn case_collisions() {
+        let project = TempProject::new(&[
+            ("pkgs/unit/ba/Bar/pkg-fun.nix", "{ }: { }\n"),
+        ]);
+        let index = project.index();
+        let config = Config::default();
+        let collision = |attr, first: &[String]| case_collision(&index, &config, attr, first);
+        assert_eq!(collision("foo", &[]), None);
+        assert_eq!(
+            collision("Foo", &["foo".to_string()]),
+            Some(PathBuf::from("./pkgs/unit/fo/foo"))
+        );
+        assert_eq!(collision("bar", &[]), Some(PathBuf::from("./pkgs/unit/ba/Bar")));
+        assert_eq!(collision("baz", &[]), None);
+
+        assert_eq!(
+            CaseFolded::existing(&index, &config).diagnostics(Severity::Error),
+            []
+        );
+    }
+
+    #[test]
+    fn existing_collisions() {
+        let project = TempProject::new(&[
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/FOO/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/Foo/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/ba/bar/pkg-fun.nix", "{ }: { }\n"),
+        ]);
+        let diagnostics = CaseFolded::existing(&project.index(), &Config::default())
+            .diagnostics(Severity::Error);
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Unit directory \"./pkgs/unit/fo/Foo\" only differs by case from \"./pkgs/unit/fo/FOO\", which collides on case-insensitive filesystems",
+                "Unit directory \"./pkgs/unit/fo/foo\" only differs by case from \"./pkgs/unit/fo/FOO\", which collides on case-insensitive filesystems",
+            ]
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert!(check_name("foo-bar_2").is_ok());
+        assert_eq!(
+            check_name("foo.bar").unwrap_err(),
+            "it contains '.', only ASCII letters, digits, `_` and `-` are supported"
+        );
+        assert!(check_name("föo").is_err());
+        assert_eq!(
+            check_name("_foo").unwrap_err(),
+            "it starts with `_`, which is reserved for attributes that aren't packages"
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// Which package each file in a directory belongs to, such that migrating a package moves exactly
+// its own files and leaves the ones of its neighbours alone.
+//
+// A file belongs to the packages whose package file transitively references it, found by
+// following references backwards until reaching the `callPackage` definitions in
+// all-packages.nix. Files that aren't referenced by any package, like `update.sh` or a README,
+// are adopted by the package owning the rest of the directory, if there's exactly one
+use crate::all_packages::AllPackages;
+use crate::index::GlobalIndex;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Owner {
+    // Only referenced by the closure of this attribute
+    Package(String),
+    // Not referenced by any package, but the rest of the directory belongs to this attribute
+    Adopted(String),
+    // Referenced by multiple packages, or from files that don't belong to a package
+    Shared,
+    // Not referenced by any package, in a directory that doesn't belong to a single package
+    Unowned,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Owner::Package(attr) => write!(f, "belongs to attribute {:?}", attr),
+            Owner::Adopted(attr) => write!(
+                f,
+                "isn't referenced by any package, but the rest of the directory belongs to attribute {:?}",
+                attr
+            ),
+            Owner::Shared => write!(f, "is shared with other packages or files"),
+            Owner::Unowned => write!(
+                f,
+                "isn't referenced by any package, and the directory doesn't belong to a single package"
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Ownership {
+    // Every file in the directory, including subdirectories
+    pub files: BTreeMap<PathBuf, Owner>,
+}
+
+impl Ownership {
+    pub fn new(index: &GlobalIndex, all_packages: &AllPackages, dir: &Path) -> Ownership {
+        let mut owners = BTreeMap::new();
+        for path in index.paths_under(dir) {
+            if !index.path_indices[path].is_dir {
+                owners.insert(path.clone(), referencing_packages(index, all_packages, dir, path));
+            }
+        }
+
+        let mut packages = BTreeSet::new();
+        let mut shared = false;
+        for (attrs, external) in owners.values() {
+            packages.extend(attrs.iter().cloned());
+            shared |= *external || attrs.len() > 1;
+        }
+        // Otherwise it's not clear who unreferenced files belong to
+        let adopter = match (packages.len(), shared) {
+            (1, false) if !referenced_from_outside(index, all_packages, dir, &owners) => {
+                packages.pop_first()
+            }
+            _ => None,
+        };
+
+        let files = owners
+            .into_iter()
+            .map(|(path, (mut attrs, external))| {
+                let owner = match (attrs.len(), external) {
+                    (0, false) => match &adopter {
+                        Some(attr) => Owner::Adopted(attr.clone()),
+                        None => Owner::Unowned,
+                    },
+                    (1, false) => Owner::Package(attrs.pop_first().unwrap()),
+                    _ => Owner::Shared,
+                };
+                (path, owner)
+            })
+            .collect();
+        Ownership { files }
+    }
+
+    // Whether the file is moved along when migrating the attribute
+    pub fn moves_with(&self, file: &Path, attr: &str) -> bool {
+        matches!(
+            self.files.get(file),
+            Some(Owner::Package(owner) | Owner::Adopted(owner)) if owner == attr
+        )
+    }
+}
+
+// The attributes whose package files transitively reference the file, and whether it's also
+// referenced by something else: an all-packages.nix reference that isn't a `callPackage`
+// definition, or a file outside the directory that isn't referenced itself
+fn referencing_packages(
+    index: &GlobalIndex,
+    all_packages: &AllPackages,
+    dir: &Path,
+    file: &Path,
+) -> (BTreeSet<String>, bool) {
+    let mut attrs = BTreeSet::new();
+    let mut external = false;
+    let mut visited = BTreeSet::from([file.to_path_buf()]);
+    let mut queue = VecDeque::from([file.to_path_buf()]);
+    while let Some(next) = queue.pop_front() {
+        let mut referenced = false;
+        for (source, reference_index) in referencers(index, &next) {
+            referenced = true;
+            if source == &all_packages.path {
+                let offset = index.path_indices[source].references[*reference_index].offset;
+                match all_packages.entry_at(offset) {
+                    // Attributes sharing a package file are migrated together
+                    Some((attr, entry)) if entry.path == next => {
+                        attrs.insert(all_packages.canonical(attr).clone());
+                    }
+                    _ => external = true,
+                }
+            } else if visited.insert(source.clone()) {
+                queue.push_back(source.clone());
+            }
+        }
+        // Unreferenced files in the directory are assigned by themselves
+        if !referenced && !next.starts_with(dir) {
+            external = true;
+        }
+    }
+    (attrs, external)
+}
+
+// The references to a file, without the ones the index records for the file's own references
+pub(crate) fn referencers<'a>(
+    index: &'a GlobalIndex,
+    file: &'a Path,
+) -> impl Iterator<Item = &'a (PathBuf, usize)> + 'a {
+    index.path_indices[file]
+        .referenced_by
+        .iter()
+        .filter(move |(source, _)| source != file)
+}
+
+// Whether the directory or anything in it is referenced by a file that doesn't belong to it, e.g.
+// with `src = ../foo` or `patches = ../foo/patches`, in which case its unreferenced files may be
+// used from there. The `callPackage` definitions in all-packages.nix don't count
+fn referenced_from_outside(
+    index: &GlobalIndex,
+    all_packages: &AllPackages,
+    dir: &Path,
+    owners: &BTreeMap<PathBuf, (BTreeSet<String>, bool)>,
+) -> bool {
+    index
+        .paths_under(dir)
+        .into_iter()
+        .flat_map(|path| &index.path_indices[path].referenced_by)
+        .any(|(source, reference_index)| {
+            if owners.contains_key(source) {
+                return false;
+            }
+            let reference = &index.path_indices[source].references[*reference_index];
+            let definition = source == &all_packages.path
+                && all_packages
+                    .entry_at(reference.offset)
+                    .is_some_and(|(_, entry)| entry.path == reference.rel_to_root);
+            !definition
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_util::TempProject;
+
+    fn owners(files: &[(&str, &str)], dir: &str) -> Vec<(String, Owner)> {
+        let project = TempProject::new(files);
+        let index = project.index();
+        let all_packages =
+            AllPackages::new(&Config::default().all_packages_path(), &index).unwrap();
+        Ownership::new(&index, &all_packages, Path::new(dir))
+            .files
+            .into_iter()
+            .map(|(path, owner)| (path.to_string_lossy().into_owned(), owner))
+            .collect()
+    }
+
+    const ALL_PACKAGES: &str = "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  other = callPackage ../tools/other { };\n}\n";
+
+    #[test]
+    fn file_in_subdirectory_referenced_from_outside() {
+        let owners = owners(
+            &[
+                ("pkgs/top-level/all-packages.nix", ALL_PACKAGES),
+                ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+                ("pkgs/tools/foo/update.sh", ""),
+                ("pkgs/tools/foo/patches/a.patch", ""),
+                ("pkgs/tools/foo/patches/b.patch", ""),
+                ("pkgs/tools/other/default.nix", "{ }: { patches = [ ../foo/patches/a.patch ]; }\n"),
+            ],
+            "./pkgs/tools/foo",
+        );
+        assert_eq!(
+            owners,
+            [
+                ("./pkgs/tools/foo/default.nix".to_string(), Owner::Package("foo".to_string())),
+                ("./pkgs/tools/foo/patches/a.patch".to_string(), Owner::Package("other".to_string())),
+                ("./pkgs/tools/foo/patches/b.patch".to_string(), Owner::Unowned),
+                ("./pkgs/tools/foo/update.sh".to_string(), Owner::Unowned),
+            ]
+        );
+    }
+
+    #[test]
+    fn subdirectory_referenced_from_outside() {
+        let owners = owners(
+            &[
+                ("pkgs/top-level/all-packages.nix", ALL_PACKAGES),
+                ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+                ("pkgs/tools/foo/update.sh", ""),
+                ("pkgs/tools/foo/patches/a.patch", ""),
+                ("pkgs/tools/other/default.nix", "{ }: { patchDir = ../foo/patches; }\n"),
+            ],
+            "./pkgs/tools/foo",
+        );
+        // The patches may be used by `other` through the directory, so they stay behind
+        assert_eq!(
+            owners,
+            [
+                ("./pkgs/tools/foo/default.nix".to_string(), Owner::Package("foo".to_string())),
+                ("./pkgs/tools/foo/patches/a.patch".to_string(), Owner::Unowned),
+                ("./pkgs/tools/foo/update.sh".to_string(), Owner::Unowned),
+            ]
+        );
+    }
+
+    #[test]
+    fn adopted_files() {
+        let owners = owners(
+            &[
+                ("pkgs/top-level/all-packages.nix", ALL_PACKAGES),
+                ("pkgs/tools/foo/default.nix", "{ }: { patches = [ ./patches/a.patch ]; }\n"),
+                ("pkgs/tools/foo/update.sh", ""),
+                ("pkgs/tools/foo/patches/a.patch", ""),
+                ("pkgs/tools/foo/patches/b.patch", ""),
+                ("pkgs/tools/other/default.nix", "{ }: { }\n"),
+            ],
+            "./pkgs/tools/foo",
+        );
+        assert_eq!(
+            owners,
+            [
+                ("./pkgs/tools/foo/default.nix".to_string(), Owner::Package("foo".to_string())),
+                ("./pkgs/tools/foo/patches/a.patch".to_string(), Owner::Package("foo".to_string())),
+                ("./pkgs/tools/foo/patches/b.patch".to_string(), Owner::Adopted("foo".to_string())),
+                ("./pkgs/tools/foo/update.sh".to_string(), Owner::Adopted("foo".to_string())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// Classifying every all-packages.nix definition by whether it can be migrated, and why not
+use crate::all_packages::{AllPackages, BlockReason};
+use crate::config::{unprefixed, Config};
+use crate::index::GlobalIndex;
+use crate::migrate;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Row {
+    pub attr: String,
+    pub line: usize,
+    // `None` if the attribute can be migrated
+    pub reason: Option<BlockReason>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// One row per attribute
+    Csv,
+    /// One row per reason with the number of attributes
+    CountsCsv,
+    /// Both the attributes and the counts
+    Json,
+}
+
+// All definitions of all-packages.nix in order, with the reason they can't be migrated
+pub fn rows(index: &GlobalIndex, all_packages: &AllPackages, config: &Config) -> Vec<Row> {
+    let mut rows: Vec<Row> = all_packages
+        .blocked
+        .iter()
+        .map(|blocked| Row {
+            attr: blocked.attr.clone(),
+            line: blocked.line,
+            reason: Some(blocked.reason),
+        })
+        .collect();
+    for (attr, entry) in all_packages.entries_in_order() {
+        let reason = match migrate::analyse(index, all_packages, config, attr) {
+            Ok(_) => None,
+            // A refusal without a category still isn't migratable
+            Err(diagnostic) => Some(BlockReason::of(&diagnostic).unwrap_or(BlockReason::Other)),
+        };
+        rows.push(Row {
+            attr: attr.clone(),
+            line: entry.line,
+            reason,
+        });
+    }
+    rows.sort_by_key(|row| row.line);
+    rows
+}
+
+// The number of attributes per reason, including reasons no attribute has
+pub fn counts(rows: &[Row]) -> BTreeMap<String, usize> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    counts.insert("migratable".to_string(), 0);
+    for reason in BlockReason::ALL {
+        counts.insert(reason.to_string(), 0);
+    }
+    for row in rows {
+        let key = row.reason.map_or("migratable".to_string(), |r| r.to_string());
+        *counts.entry(key).or_default() += 1;
+    }
+    counts
+}
+
+pub fn render(format: ReportFormat, all_packages: &AllPackages, rows: &[Row]) -> String {
+    match format {
+        ReportFormat::Csv => {
+            let mut csv = String::from("attribute,file,line,reason\n");
+            let file = unprefixed(&all_packages.path).display().to_string();
+            for row in rows {
+                let reason = row.reason.map_or("migratable".to_string(), |r| r.to_string());
+                csv += &format!("{},{},{},{}\n", csv_field(&row.attr), csv_field(&file), row.line, reason);
+            }
+            csv
+        }
+        ReportFormat::CountsCsv => {
+            let mut csv = String::from("reason,count\n");
+            for (reason, count) in counts(rows) {
+                csv += &format!("{},{}\n", reason, count);
+            }
+            csv
+        }
+        ReportFormat::Json => {
+            let value: Value = json!({
+                "file": unprefixed(&all_packages.path),
+                "attributes": rows,
+                "counts": counts(rows),
+            });
+            format!("{}\n", value)
+        }
+    }
+}
+
+// Quotes a field if needed, e.g. for attribute names that are strings
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempProject;
+
+    fn row(attr: &str, line: usize, reason: Option<BlockReason>) -> Row {
+        Row {
+            attr: attr.to_string(),
+            line,
+            reason,
+        }
+    }
+
+    #[test]
+    fn rows_in_order() {
+        let project = TempProject::new(&[
+            (
+                "pkgs/top-level/all-packages.nix",
+                "{ callPackage }:\n{\n  foo = callPackage ../tools/foo { };\n  bar = 1;\n  baz = callPackage ../tools/baz { };\n  \"q,x\" = callPackage ../tools/foo { };\n}\n",
+            ),
+            ("pkgs/tools/foo/default.nix", "{ }: { }\n"),
+            ("pkgs/tools/baz/default.nix", "{ }: { src = ../foo; }\n"),
+        ]);
+        let index = project.index();
+        let config = Config::default();
+        let all_packages = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        let rows: Vec<_> = rows(&index, &all_packages, &config)
+            .into_iter()
+            .map(|row| (row.attr, row.line, row.reason))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("foo".to_string(), 3, Some(BlockReason::ExternalReferencer)),
+                ("bar".to_string(), 4, Some(BlockReason::NotCallPackage)),
+                ("baz".to_string(), 5, Some(BlockReason::EscapingReference)),
+                ("\"q,x\"".to_string(), 6, Some(BlockReason::NonIdentifier)),
+            ]
+        );
+
+        assert_eq!(
+            render(ReportFormat::Csv, &all_packages, &[row("foo", 3, None), row("\"q,x\"", 6, Some(BlockReason::NonIdentifier))]),
+            "attribute,file,line,reason\nfoo,pkgs/top-level/all-packages.nix,3,migratable\n\"\"\"q,x\"\"\",pkgs/top-level/all-packages.nix,6,non-identifier\n"
+        );
+    }
+
+    #[test]
+    fn counts_include_every_reason() {
+        let counts = counts(&[
+            row("foo", 1, None),
+            row("bar", 2, None),
+            row("baz", 3, Some(BlockReason::Other)),
+            row("qux", 4, Some(BlockReason::NotCallPackage)),
+        ]);
+        assert_eq!(counts.len(), BlockReason::ALL.len() + 1);
+        assert_eq!(counts["migratable"], 2);
+        assert_eq!(counts["other"], 1);
+        assert_eq!(counts["not-call-package"], 1);
+        assert_eq!(counts["invalid-path"], 0);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("foo"), "foo");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("\"foo\""), "\"\"\"foo\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+    }
+}
@@ -0,0 +1,172 @@
+// Conversion of diagnostics into a SARIF 2.1.0 log, see
+// https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
+use serde_json::{json, Value};
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+pub fn log(diagnostics: &[Diagnostic]) -> Value {
+    json!({
+        "$schema": SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/nixpkgs-architecture/nix-spp",
+                    "rules": Code::ALL.iter().map(rule).collect::<Vec<_>>(),
+                }
+            },
+            "originalUriBaseIds": {
+                "%SRCROOT%": {
+                    "description": { "text": "The root of the nixpkgs checkout" }
+                }
+            },
+            "results": diagnostics.iter().map(result).collect::<Vec<_>>(),
+        }]
+    })
+}
+
+fn rule(code: &Code) -> Value {
+    json!({
+        "id": code.id(),
+        "name": format!("{:?}", code),
+        "shortDescription": { "text": code.description() },
+    })
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Note => "note",
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    }
+}
+
+fn result(diagnostic: &Diagnostic) -> Value {
+    let related: Vec<_> = diagnostic
+        .related
+        .iter()
+        .enumerate()
+        .map(|(id, related)| {
+            json!({
+                "id": id,
+                "physicalLocation": physical_location(&related.location),
+                "message": { "text": related.message },
+            })
+        })
+        .collect();
+    json!({
+        "ruleId": diagnostic.code.id(),
+        "ruleIndex": Code::ALL.iter().position(|code| *code == diagnostic.code),
+        "level": level(diagnostic.severity),
+        "message": { "text": diagnostic.message },
+        "locations": [{ "physicalLocation": physical_location(&diagnostic.location) }],
+        "relatedLocations": related,
+    })
+}
+
+fn physical_location(location: &Location) -> Value {
+    let mut physical = json!({
+        "artifactLocation": {
+            "uri": uri(&location.display_file()),
+            "uriBaseId": "%SRCROOT%",
+        }
+    });
+    if let Some(line) = location.line {
+        let mut region = json!({ "startLine": line });
+        if let Some(column) = location.column {
+            region["startColumn"] = json!(column);
+        }
+        physical["region"] = region;
+    }
+    physical
+}
+
+// Relative URI reference for a path, only escaping what can occur in nixpkgs file names
+fn uri(path: &str) -> String {
+    path.replace('%', "%25")
+        .replace(' ', "%20")
+        .replace('#', "%23")
+        .replace('?', "%3F")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn results() {
+        let diagnostics = [
+            Diagnostic::new(
+                Severity::Error,
+                Code::IncomingReference,
+                Location::new(Path::new("./pkgs/tools/foo bar/100%#1?.nix"), 3, 7),
+                "Referenced from outside".to_string(),
+            )
+            .with_related(
+                Location::new(Path::new("./pkgs/tools/baz/default.nix"), 2, 9),
+                "Path reference \"../foo\" would break".to_string(),
+            ),
+            Diagnostic::new(
+                Severity::Note,
+                Code::Converted,
+                Location::file(Path::new("./pkgs/unit/fo/foo")),
+                "Moved".to_string(),
+            ),
+        ];
+        let log = log(&diagnostics);
+        let run = &log["runs"][0];
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), Code::ALL.len());
+        assert_eq!(
+            run["results"],
+            json!([
+                {
+                    "ruleId": "NSPP011",
+                    "ruleIndex": 10,
+                    "level": "error",
+                    "message": { "text": "Referenced from outside" },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": {
+                                "uri": "pkgs/tools/foo%20bar/100%25%231%3F.nix",
+                                "uriBaseId": "%SRCROOT%",
+                            },
+                            "region": { "startLine": 3, "startColumn": 7 },
+                        }
+                    }],
+                    "relatedLocations": [{
+                        "id": 0,
+                        "physicalLocation": {
+                            "artifactLocation": {
+                                "uri": "pkgs/tools/baz/default.nix",
+                                "uriBaseId": "%SRCROOT%",
+                            },
+                            "region": { "startLine": 2, "startColumn": 9 },
+                        },
+                        "message": { "text": "Path reference \"../foo\" would break" },
+                    }],
+                },
+                {
+                    "ruleId": "NSPP018",
+                    "ruleIndex": 17,
+                    "level": "note",
+                    "message": { "text": "Moved" },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": {
+                                "uri": "pkgs/unit/fo/foo",
+                                "uriBaseId": "%SRCROOT%",
+                            },
+                        }
+                    }],
+                    "relatedLocations": [],
+                },
+            ])
+        );
+        // The rule index points at the rule of the result
+        assert_eq!(run["tool"]["driver"]["rules"][10]["id"], "NSPP011");
+    }
+}
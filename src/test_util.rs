@@ -0,0 +1,53 @@
+// Helpers for tests that need a project on disk
+use crate::index::GlobalIndex;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A project in a fresh temporary directory, removed again when dropped
+pub struct TempProject {
+    pub root: PathBuf,
+}
+
+impl TempProject {
+    // Creates the project with the given files, as paths relative to the root and contents
+    pub fn new(files: &[(&str, &str)]) -> TempProject {
+        let root = std::env::temp_dir().join(format!(
+            "nix-spp-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let project = TempProject { root };
+        for (path, contents) in files {
+            project.write(path, contents);
+        }
+        project
+    }
+
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.root.join(path)).unwrap()
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.root.join(path).exists()
+    }
+
+    pub fn index(&self) -> GlobalIndex {
+        GlobalIndex::new(&self.root, &[]).unwrap()
+    }
+}
+
+impl Drop for TempProject {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::all_packages::AllPackages;
+use crate::config::{rel_to_root, relative_reference, Config};
+use crate::diagnostic::{Code, Diagnostic, Location, Severity};
+use crate::index::GlobalIndex;
+use crate::migrate::{move_files, undo_moves, Mover};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+// Moving a package out of its unit directory and defining it in all-packages.nix again, e.g.
+// because it needs custom arguments
+#[derive(Debug, Clone)]
+pub struct Unmigration {
+    pub attr: String,
+    pub from: PathBuf,
+    pub to: PathBuf,
+    // Pairs of old and new paths, relative to the root
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    // The new contents of all-packages.nix
+    pub all_packages: String,
+}
+
+// Determines how to move a package from `<unit dir>/<shard>/<attr>` to `to`, renaming the package
+// file to `default.nix`. The package can't have any references crossing its unit directory, and
+// the attribute can't be defined in all-packages.nix already. This doesn't change anything on
+// disk
+pub fn analyse(
+    index: &GlobalIndex,
+    all_packages: &AllPackages,
+    config: &Config,
+    attr: &str,
+    to: &Path,
+) -> Result<Unmigration, Vec<Diagnostic>> {
+    let from = config.unit_dir_for(attr);
+    let to = rel_to_root(to);
+    let location = Location::file(&from);
+    let error = |code, message: String| vec![Diagnostic::new(Severity::Error, code, location.clone(), message)];
+
+    if !index.path_indices.get(&from).is_some_and(|p| p.is_dir) {
+        return Err(error(
+            Code::MigrationFailed,
+            format!("Cannot move attribute {:?} back, because {:?} doesn't exist", attr, from),
+        ));
+    }
+    if all_packages.defines(attr) {
+        return Err(vec![Diagnostic::new(
+            Severity::Error,
+            Code::MigrationFailed,
+            Location::file(&all_packages.path),
+            format!("Cannot move attribute {:?} back, because it's already defined", attr),
+        )]);
+    }
+    if index.path_indices.contains_key(&to) || index.root.join(&to).exists() {
+        return Err(error(
+            Code::MigrationFailed,
+            format!("Cannot move attribute {:?} back, because {:?} already exists", attr, to),
+        ));
+    }
+
+    let mut files: Vec<_> = index
+        .path_indices
+        .iter()
+        .filter(|(path, path_index)| !path_index.is_dir && path.starts_with(&from))
+        .map(|(path, _)| path.clone())
+        .collect();
+    files.sort();
+
+    let package_file = from.join(config.package_file());
+    if !files.contains(&package_file) {
+        return Err(error(
+            Code::MigrationFailed,
+            format!("Cannot move attribute {:?} back, because {:?} doesn't exist", attr, package_file),
+        ));
+    }
+
+    let mut diagnostics = vec![];
+    for file in &files {
+        let path_index = index.path_indices.get(file).unwrap();
+        for (referenced_by, reference_index) in &path_index.referenced_by {
+            if referenced_by.starts_with(&from) {
+                continue;
+            }
+            let reference = &index.path_indices.get(referenced_by).unwrap().references[*reference_index];
+            diagnostics.push(
+                Diagnostic::new(
+                    Severity::Error,
+                    Code::IncomingReference,
+                    location.clone(),
+                    format!("Cannot move attribute {:?} back, because its file {:?} is referenced from outside", attr, file),
+                )
+                .with_related(
+                    Location::new(referenced_by, reference.line, reference.column),
+                    format!("Path reference {:?} would break", reference.text),
+                ),
+            );
+        }
+        for reference in &path_index.references {
+            if reference.movable_ancestor.starts_with(&from) {
+                continue;
+            }
+            diagnostics.push(
+                Diagnostic::new(
+                    Severity::Error,
+                    Code::OutgoingReference,
+                    location.clone(),
+                    format!("Cannot move attribute {:?} back, because it references a path outside its directory", attr),
+                )
+                .with_related(
+                    Location::new(file, reference.line, reference.column),
+                    format!("Path reference {:?} would break", reference.text),
+                ),
+            );
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let moves = files
+        .into_iter()
+        .map(|old| {
+            let new = if old == package_file {
+                to.join("default.nix")
+            } else {
+                to.join(old.strip_prefix(&from).unwrap())
+            };
+            (old, new)
+        })
+        .collect();
+
+    let reference = relative_reference(all_packages.path.parent().unwrap(), &to);
+    let all_packages = all_packages
+        .with_inserted(attr, &reference)
+        .map_err(|err| error(Code::MigrationFailed, format!("{:#}", err)))?;
+
+    Ok(Unmigration {
+        attr: attr.to_string(),
+        from,
+        to,
+        moves,
+        all_packages,
+    })
+}
+
+// Moves the files of the package and writes all-packages.nix with `write`, which isn't done for
+// a dry run. Either all files are moved or none, also if writing fails
+pub fn apply(
+    mover: &dyn Mover,
+    unmigration: &Unmigration,
+    write: impl FnOnce(&str) -> Result<()>,
+) -> Result<()> {
+    move_files(mover, &unmigration.moves)?;
+    write(&unmigration.all_packages).map_err(|err| undo_moves(mover, &unmigration.moves, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrate::{self, shadowed_definitions, FileSystem};
+    use crate::test_util::TempProject;
+    use anyhow::bail;
+
+    const ALL_PACKAGES: &str = "{ callPackage }:\n{\n  bar = callPackage ../tools/bar { };\n}\n";
+
+    fn unmigration(project: &TempProject) -> Result<Unmigration, Vec<Diagnostic>> {
+        let index = project.index();
+        let config = Config::default();
+        let ap = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        analyse(&index, &ap, &config, "foo", Path::new("pkgs/tools/foo"))
+    }
+
+    #[test]
+    fn missing_package_file() {
+        let project = TempProject::new(&[
+            ("pkgs/top-level/all-packages.nix", ALL_PACKAGES),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/foo/default.nix", "{ }: { }\n"),
+        ]);
+        let diagnostics = unmigration(&project).unwrap_err();
+        assert_eq!(
+            diagnostics[0].message,
+            "Cannot move attribute \"foo\" back, because \"./pkgs/unit/fo/foo/pkg-fun.nix\" doesn't exist"
+        );
+    }
+
+    #[test]
+    fn failed_write_undoes_moves() {
+        let project = TempProject::new(&[
+            ("pkgs/top-level/all-packages.nix", ALL_PACKAGES),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/foo/fix.patch", ""),
+        ]);
+        let unmigration = unmigration(&project).unwrap();
+        let mover = FileSystem {
+            root: project.root.clone(),
+        };
+        let result = apply(&mover, &unmigration, |_| bail!("Disk full"));
+        assert_eq!(result.unwrap_err().to_string(), "Disk full");
+        assert!(project.exists("pkgs/unit/fo/foo/pkg-fun.nix"));
+        assert!(project.exists("pkgs/unit/fo/foo/fix.patch"));
+        assert!(!project.exists("pkgs/tools/foo/default.nix"));
+    }
+
+    #[test]
+    fn unmigrated_package_is_clean() {
+        let project = TempProject::new(&[
+            ("pkgs/top-level/all-packages.nix", ALL_PACKAGES),
+            ("pkgs/tools/bar/default.nix", "{ }: { }\n"),
+            ("pkgs/unit/fo/foo/pkg-fun.nix", "{ }: { }\n"),
+            ("pkgs/unit/ba/baz/pkg-fun.nix", "{ }: { }\n"),
+        ]);
+        let config = Config::default();
+        let unmigration = unmigration(&project).unwrap();
+        let mover = FileSystem {
+            root: project.root.clone(),
+        };
+        let all_packages_path = project.root.join("pkgs/top-level/all-packages.nix");
+        apply(&mover, &unmigration, |contents| Ok(std::fs::write(&all_packages_path, contents)?)).unwrap();
+        let removed = migrate::remove_empty_dirs(&project.root, &unmigration.moves, &config.unit_dir()).unwrap();
+        assert_eq!(removed, [PathBuf::from("./pkgs/unit/fo/foo"), PathBuf::from("./pkgs/unit/fo")]);
+        assert!(project.exists("pkgs/unit/ba/baz"));
+
+        // Neither shadowed by nor colliding with the unit directory it came from
+        let index = project.index();
+        let ap = AllPackages::new(&config.all_packages_path(), &index).unwrap();
+        assert!(shadowed_definitions(&index, &ap, &config, Severity::Error).is_empty());
+        let migration = migrate::analyse(&index, &ap, &config, "foo").unwrap();
+        assert_eq!(migration.unit_dir, PathBuf::from("./pkgs/unit/fo/foo"));
+    }
+}